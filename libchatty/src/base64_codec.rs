@@ -21,3 +21,34 @@ pub fn deserialize<'de, D: Deserializer<'de>>(
     VerifyingKey::try_from(&decoded[..32])
         .map_err(|e| serde::de::Error::custom(e))
 }
+
+/// Same encoding for maps keyed by public keys, e.g. in settings files
+pub mod keys {
+    use super::*;
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer, V: Serialize>(
+        map: &HashMap<VerifyingKey, V>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_map(map.iter().map(|(key, value)| {
+            (BASE64_STANDARD.encode(key.as_bytes()), value)
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+        d: D,
+    ) -> Result<HashMap<VerifyingKey, V>, D::Error> {
+        HashMap::<String, V>::deserialize(d)?
+            .into_iter()
+            .map(|(base64, value)| {
+                let decoded: Vec<u8> = BASE64_STANDARD
+                    .decode(base64.as_bytes())
+                    .map_err(serde::de::Error::custom)?;
+                let key = VerifyingKey::try_from(decoded.as_slice())
+                    .map_err(serde::de::Error::custom)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
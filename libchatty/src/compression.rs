@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::system::FileMetadata;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Picks the compression used for a given file. Files that are already
+    /// compressed are always sent as they are.
+    pub fn for_file(self, file: &FileMetadata) -> Self {
+        match &file.filetype {
+            Some(mime) if mime.is_compressed() => Compression::None,
+            _ => self,
+        }
+    }
+
+    pub fn compress(self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Zstd => zstd::bulk::compress(&data, ZSTD_LEVEL),
+        }
+    }
+
+    /// Decompresses data that is expected to be at most `max_len` bytes long
+    pub fn decompress(self, data: Vec<u8>, max_len: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Zstd => zstd::bulk::decompress(&data, max_len),
+        }
+    }
+}
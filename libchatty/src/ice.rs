@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashSet, net::SocketAddr};
+use strum_macros::Display;
+
+/// How a candidate address was learned, which decides how much it's
+/// preferred. The values follow the type preferences of ICE (RFC 8445).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Display)]
+pub enum CandidateKind {
+    /// An address of one of the peer's own interfaces. Peers on the same
+    /// network reach each other this way, without hairpinning through their
+    /// NAT.
+    #[strum(to_string = "host")]
+    Host,
+    /// The address a relay sees the peer connecting from
+    #[strum(to_string = "reflexive")]
+    Reflexive,
+    /// The address a connection from the peer arrived from
+    #[strum(to_string = "peer reflexive")]
+    PeerReflexive,
+    /// A relay that can tunnel connections to the peer
+    #[strum(to_string = "relayed")]
+    Relayed,
+}
+
+impl CandidateKind {
+    fn preference(&self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::PeerReflexive => 110,
+            CandidateKind::Reflexive => 100,
+            CandidateKind::Relayed => 0,
+        }
+    }
+}
+
+/// An address that a peer may be reachable at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+}
+
+impl Candidate {
+    pub fn new(kind: CandidateKind, addr: SocketAddr) -> Self {
+        Self { kind, addr }
+    }
+
+    /// Higher is better. Within a kind IPv6 addresses come first, as they
+    /// rarely need hole punching.
+    pub fn priority(&self) -> u32 {
+        let family = if self.addr.is_ipv6() { 0xffff } else { 0x7fff };
+        (self.kind.preference() << 24) | (family << 8) | 0xff
+    }
+}
+
+/// Orders candidates by priority and drops duplicate addresses, keeping the
+/// best kind of each. IPv4 addresses mapped into IPv6 by dual-stack sockets
+/// are turned back into IPv4 ones first.
+pub fn sort_candidates(candidates: &mut Vec<Candidate>) {
+    for candidate in candidates.iter_mut() {
+        candidate.addr.set_ip(candidate.addr.ip().to_canonical());
+    }
+
+    candidates.sort_by_key(|candidate| Reverse(candidate.priority()));
+    let mut seen = HashSet::new();
+    candidates.retain(|candidate| seen.insert(candidate.addr));
+}
@@ -2,7 +2,7 @@ use crate::{messaging::UserMessage, system::{FileHandle, Hash}};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{
     fs,
     net::SocketAddr,
@@ -22,13 +22,33 @@ pub struct UserMetadata {
 pub struct User {
     metadata: UserMetadata,
     public_key: VerifyingKey,
+    // Relays this user can be reached through
+    relays: Vec<Relay>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// Identity files exported before users advertised their relays
+#[derive(Deserialize)]
+struct LegacyUser {
+    metadata: UserMetadata,
+    public_key: VerifyingKey,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Relay {
     pub addr: SocketAddr,
     #[serde(with = "crate::base64_codec")]
     pub public_key: VerifyingKey,
+    /// Relays with lower values are preferred
+    #[serde(default)]
+    pub priority: u32,
+    /// Invite to a private relay, issued with `p2p-relay --invite`
+    #[serde(default)]
+    pub invite: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RelayList {
+    pub relays: Vec<Relay>,
 }
 
 impl Relay {
@@ -43,10 +63,50 @@ impl Relay {
     }
 }
 
+impl RelayList {
+    /// Loads relays sorted by priority. A file without a `[[relays]]`
+    /// table describes a single relay, as they did before.
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let invalid =
+            |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+
+        let config = std::fs::read_to_string(path)?;
+        let table = toml::from_str::<toml::Table>(&config).map_err(invalid)?;
+        let mut list = if table.contains_key("relays") {
+            toml::from_str::<RelayList>(&config).map_err(invalid)?
+        }
+        else {
+            RelayList {
+                relays: vec![toml::from_str::<Relay>(&config).map_err(invalid)?],
+            }
+        };
+
+        list.relays.sort_by_key(|relay| relay.priority);
+        Ok(list)
+    }
+
+    pub fn save(&self, path: &Path) {
+        let serialized = toml::to_string(self).unwrap();
+        fs::write(path, serialized).unwrap();
+    }
+}
+
 impl User {
     pub fn load_file(path: &Path) -> User {
         let serialized = fs::read(path).unwrap();
-        postcard::from_bytes(&serialized).unwrap()
+        postcard::from_bytes(&serialized).unwrap_or_else(|_| {
+            let legacy: LegacyUser = postcard::from_bytes(&serialized).unwrap();
+            User {
+                metadata: legacy.metadata,
+                public_key: legacy.public_key,
+                relays: Vec::new(),
+            }
+        })
+    }
+
+    pub fn with_relays(mut self, relays: Vec<Relay>) -> Self {
+        self.relays = relays;
+        self
     }
 
     pub fn save_file(&self, path: &Path) {
@@ -66,6 +126,7 @@ impl Myself {
         User {
             metadata: self.metadata.clone(),
             public_key: self.private_key.verifying_key(),
+            relays: Vec::new(),
         }
     }
 
@@ -147,7 +208,24 @@ pub struct UserDb {
     pub myself: Myself, // TODO: Make this a list of multiple identities
     pub remote: HashMap<VerifyingKey, UserMetadata>,
     pub messages: HashMap<VerifyingKey, Vec<UserMessage>>,
-    pub files: HashMap<Hash, FileHandle>
+    pub files: HashMap<Hash, FileHandle>,
+    // Relays advertised by remote users
+    pub relays: HashMap<VerifyingKey, Vec<Relay>>,
+    // Addresses where users can be reached without a relay
+    pub addresses: HashMap<VerifyingKey, SocketAddr>,
+    // Contacts whose keys were compared out of band
+    pub verified: HashSet<VerifyingKey>,
+}
+
+// Databases created before relay hints, address hints and verified
+// contacts were stored
+#[derive(Deserialize)]
+struct LegacyUserDb {
+    path: PathBuf,
+    myself: Myself,
+    remote: HashMap<VerifyingKey, UserMetadata>,
+    messages: HashMap<VerifyingKey, Vec<UserMessage>>,
+    files: HashMap<Hash, FileHandle>,
 }
 
 // TODO: Make this safe - implement error handling!
@@ -160,12 +238,18 @@ impl UserDb {
             myself,
             remote: HashMap::new(),
             messages: HashMap::new(),
-            files: HashMap::new()
+            files: HashMap::new(),
+            relays: HashMap::new(),
+            addresses: HashMap::new(),
+            verified: HashSet::new(),
         }
     }
 
     pub fn add_user(&mut self, user: User) {
         self.remote.insert(user.public_key, user.metadata);
+        if !user.relays.is_empty() {
+            self.relays.insert(user.public_key, user.relays);
+        }
     }
 
     pub fn add_file(&mut self, file: FileHandle) {
@@ -179,7 +263,20 @@ impl UserDb {
 
     pub fn load(path: &Path) -> Self {
         let serialized = fs::read(path).unwrap();
-        postcard::from_bytes(&serialized).unwrap()
+        postcard::from_bytes(&serialized).unwrap_or_else(|_| {
+            let legacy: LegacyUserDb = postcard::from_bytes(&serialized).unwrap();
+
+            Self {
+                path: legacy.path,
+                myself: legacy.myself,
+                remote: legacy.remote,
+                messages: legacy.messages,
+                files: legacy.files,
+                relays: HashMap::new(),
+                addresses: HashMap::new(),
+                verified: HashSet::new(),
+            }
+        })
     }
 
     pub fn get_user_data(&self) -> User {
@@ -0,0 +1,55 @@
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+/// Permission to register with a private relay, signed by the relay itself.
+/// An invite is bound to a single user and may expire.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invite {
+    pub invitee: VerifyingKey,
+    pub expires: Option<DateTime<Utc>>,
+    signature: Signature,
+}
+
+impl Invite {
+    pub fn new(
+        relay: &SigningKey,
+        invitee: VerifyingKey,
+        expires: Option<DateTime<Utc>>,
+    ) -> Self {
+        let signature = relay.sign(&Self::signed_data(&invitee, &expires));
+
+        Self {
+            invitee,
+            expires,
+            signature,
+        }
+    }
+
+    /// Checks that the invite was issued by the relay for the given user
+    /// and hasn't expired yet
+    pub fn verify(&self, relay: &VerifyingKey, invitee: &VerifyingKey) -> bool {
+        let data = Self::signed_data(&self.invitee, &self.expires);
+
+        self.invitee == *invitee
+            && self.expires.is_none_or(|expires| expires > Utc::now())
+            && relay.verify(&data, &self.signature).is_ok()
+    }
+
+    pub fn encode(&self) -> String {
+        BASE64_STANDARD.encode(postcard::to_allocvec(self).unwrap())
+    }
+
+    pub fn decode(invite: &str) -> Option<Self> {
+        let decoded = BASE64_STANDARD.decode(invite.trim()).ok()?;
+        postcard::from_bytes(&decoded).ok()
+    }
+
+    fn signed_data(
+        invitee: &VerifyingKey,
+        expires: &Option<DateTime<Utc>>,
+    ) -> Vec<u8> {
+        postcard::to_allocvec(&("aluminum invite", invitee, expires)).unwrap()
+    }
+}
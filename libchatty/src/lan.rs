@@ -0,0 +1,118 @@
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils;
+
+/// Port that announcements are broadcast to
+pub const LAN_PORT: u16 = 55009;
+
+/// Link-local multicast group that announcements are sent to over IPv6,
+/// which has no broadcast
+pub const LAN_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x114);
+
+/// How long a tag stays the same. Rotating them keeps bystanders on the
+/// network from following someone around by their tags.
+pub const TAG_EPOCH: Duration = Duration::from_secs(600);
+
+pub type LanTag = [u8; 32];
+
+/// Broadcast periodically so that contacts on the same network can connect
+/// directly. Only a contact can tell which of the tags is meant for them,
+/// and who the announcement is from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LanAnnouncement {
+    /// Port of the QUIC endpoint that peers should connect to
+    pub port: u16,
+    pub tags: Vec<LanTag>,
+}
+
+/// Derives the tag that `announcer` broadcasts for the contact on the other
+/// side of `token`
+pub fn lan_tag(
+    token: &utils::PresenceToken,
+    announcer: &VerifyingKey,
+    epoch: u64,
+) -> LanTag {
+    let mut hasher = blake3::Hasher::new_keyed(token);
+    hasher.update(announcer.as_bytes());
+    hasher.update(&epoch.to_le_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+pub fn current_epoch() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / TAG_EPOCH.as_secs()
+}
+
+impl LanAnnouncement {
+    pub fn new(me: &SigningKey, contacts: &[VerifyingKey], port: u16) -> Self {
+        let announcer = me.verifying_key();
+        let epoch = current_epoch();
+
+        let tags = contacts
+            .iter()
+            .map(|contact| {
+                let token = utils::presence_token(me, contact);
+                lan_tag(&token, &announcer, epoch)
+            })
+            .collect();
+
+        Self { port, tags }
+    }
+
+    /// Checks whether `contact` made this announcement. The previous epoch
+    /// is accepted too, the clocks of two peers are never exactly in sync.
+    pub fn is_from(&self, me: &SigningKey, contact: &VerifyingKey) -> bool {
+        let token = utils::presence_token(me, contact);
+        let epoch = current_epoch();
+
+        [epoch, epoch.saturating_sub(1)].iter().any(|epoch| {
+            self.tags.contains(&lan_tag(&token, contact, *epoch))
+        })
+    }
+
+    pub fn encode(&self) -> postcard::Result<Vec<u8>> {
+        postcard::to_allocvec(self)
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        postcard::from_bytes(packet).ok()
+    }
+}
+
+/// Binds the socket that announcements are sent from and received on.
+/// Several clients on one machine can share the port.
+pub fn bind_discovery_socket() -> io::Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, LAN_PORT));
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// Binds the IPv6 counterpart of the discovery socket and joins the
+/// multicast group on the default interface
+pub fn bind_discovery_socket_v6() -> io::Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, LAN_PORT));
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.join_multicast_v6(&LAN_GROUP_V6, 0)?;
+
+    Ok(socket.into())
+}
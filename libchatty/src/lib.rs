@@ -1,10 +1,19 @@
 mod base64_codec;
+pub mod compression;
+pub mod ice;
 pub mod identity;
+pub mod invite;
+pub mod lan;
 pub mod messaging;
 pub mod utils;
 pub mod system;
 pub mod mime;
+pub mod nat;
+pub mod pieces;
+pub mod policy;
 pub mod quinn_session;
+pub mod settings;
+pub mod store;
 
 pub use dissonance::noise_codec;
 pub use dissonance::noise_session;
@@ -3,7 +3,14 @@ use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr, path::PathBuf};
 use enum_as_inner::EnumAsInner;
 use chrono::{DateTime, Utc};
-use crate::system::{FileMetadata, Hash};
+use strum_macros::Display;
+use crate::{
+    compression::Compression,
+    ice::Candidate,
+    invite::Invite,
+    system::{FileMetadata, Hash},
+    utils::PresenceToken,
+};
 
 // TODO
 // Rename RelayRequest to UserToRelayMessage
@@ -16,13 +23,58 @@ pub enum RelayRequest {
     GetUser(VerifyingKey),
     Ack,
     Bye,
+    /// Registers with a private relay using an invite it issued
+    RegisterInvited(VerifyingKey, Invite),
+    /// Presence tokens of the contacts allowed to see that we're online
+    SetPresenceTokens(Vec<PresenceToken>),
+    /// Replaces the set of contacts we want presence updates about
+    Subscribe(Vec<(VerifyingKey, PresenceToken)>),
+    /// Sent instead of `Register` by relays that federate with each other
+    Federate(VerifyingKey),
+    /// A `GetUser` forwarded by a federated relay, along with the caller
+    /// that the callee should expect a connection from
+    Lookup {
+        callee: VerifyingKey,
+        caller: VerifyingKey,
+        caller_candidates: Vec<Candidate>,
+    },
+    /// Asks for the address the relay sees us connecting from. It may be
+    /// sent instead of `Register`, in which case the relay closes the
+    /// connection after answering.
+    GetReflexiveAddress,
+    /// Candidates we gathered ourselves, the relay adds the reflexive one
+    SetCandidates(Vec<Candidate>),
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, EnumAsInner)]
 pub enum RelayResponse {
-    UserAddress(Option<SocketAddr>),
-    AwaitConnection(VerifyingKey, SocketAddr),
+    /// Candidates the user can be reached at, best first
+    UserAddress(Vec<Candidate>),
+    AwaitConnection(VerifyingKey, Vec<Candidate>),
     Ack,
+    Presence(VerifyingKey, bool),
+    /// Our address as seen by the relay, along with a second port the relay
+    /// can be probed on
+    ReflexiveAddress {
+        observed: SocketAddr,
+        probe_port: Option<u16>,
+    },
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Display)]
+pub enum ErrorCode {
+    #[strum(to_string = "not allowed")]
+    NotAllowed,
+    #[strum(to_string = "rate limited")]
+    RateLimited,
+    /// The user isn't connected to the relay or any relay it federates with
+    #[strum(to_string = "unknown user")]
+    UnknownUser,
+    /// The request wasn't expected at this point. The relay closes the
+    /// connection afterwards.
+    #[strum(to_string = "protocol violation")]
+    ProtocolViolation,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, EnumAsInner)]
@@ -31,6 +83,21 @@ pub enum PeerPacket {
     GetFile(Hash),
     Ack,
     Bye,
+    GetPieceHashes(Hash),
+    PieceHashes(Hash, Vec<Hash>),
+    GetPiece(Hash, u64, Compression),
+    Piece(Hash, u64, Compression, Vec<u8>),
+    NotFound(Hash),
+    /// Sent by the initiator on the candidate pair that won the
+    /// connectivity checks, the responder drops the others
+    Nominate,
+    /// Answered with a `Pong` carrying the same nonce
+    Ping(u64),
+    Pong(u64),
+    /// The leading part of a piece, the rest follows in further chunks and
+    /// finally a `Piece`. Large pieces are split up so that they don't hold
+    /// up the other packets on the stream.
+    PieceChunk(Hash, u64, Vec<u8>),
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -32,6 +32,43 @@ impl Mime {
     pub fn essence_str(&self) -> &str {
         self.0.essence_str()
     }
+
+    /// Checks whether files of this type are already compressed
+    pub fn is_compressed(&self) -> bool {
+        let type_ = self.type_();
+        let subtype = self.subtype();
+
+        if type_ == mime::VIDEO || type_ == mime::AUDIO {
+            true
+        }
+        else if type_ == mime::IMAGE {
+            !matches!(
+                subtype.as_str(),
+                "bmp" | "svg" | "x-icon" | "vnd.microsoft.icon"
+            )
+        }
+        else if type_ == mime::APPLICATION {
+            matches!(
+                subtype.as_str(),
+                "zip"
+                    | "gzip"
+                    | "zstd"
+                    | "x-bzip2"
+                    | "x-xz"
+                    | "x-lzip"
+                    | "x-7z-compressed"
+                    | "vnd.rar"
+                    | "x-rar-compressed"
+                    | "epub+zip"
+                    | "java-archive"
+                    | "vnd.android.package-archive"
+                    | "pdf"
+            )
+        }
+        else {
+            false
+        }
+    }
 }
 
 impl Serialize for Mime {
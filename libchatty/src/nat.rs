@@ -0,0 +1,203 @@
+use std::net::SocketAddr;
+use strum_macros::Display;
+
+// Port steps larger than this look random rather than sequential
+const MAX_PREDICTABLE_DELTA: i32 = 16;
+
+/// How our NAT maps the local port to public ones, judged by the addresses
+/// that different relays (or relay ports) see us connecting from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum NatType {
+    #[strum(to_string = "open (no NAT)")]
+    Open,
+    /// The same public address is used for every destination
+    #[strum(to_string = "endpoint independent")]
+    EndpointIndependent,
+    /// Every destination gets a new port, allocated in steps of `delta`
+    #[strum(to_string = "sequential (step {delta})")]
+    Sequential { delta: i32 },
+    /// Every destination gets an unpredictable port
+    #[strum(to_string = "symmetric")]
+    Symmetric,
+    /// There weren't enough observations to tell
+    #[strum(to_string = "unknown")]
+    Unknown,
+}
+
+/// How peer connections should be attempted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum PunchStrategy {
+    #[strum(to_string = "direct hole punching")]
+    Direct,
+    /// Punch towards the ports following the one that the relay saw too
+    #[strum(to_string = "hole punching with port prediction")]
+    PortPrediction,
+    /// Hole punching won't work, go through the relay right away
+    #[strum(to_string = "relay only")]
+    Relay,
+}
+
+impl NatType {
+    /// Classifies the NAT from the public addresses observed by different
+    /// destinations, in the order they were contacted. Only observations in
+    /// the address family of `local` count, an IPv6 relay always sees a
+    /// different address than an IPv4 one.
+    pub fn classify(local: SocketAddr, observed: &[SocketAddr]) -> Self {
+        let canonical = |addr: &SocketAddr| {
+            SocketAddr::new(addr.ip().to_canonical(), addr.port())
+        };
+        let local = canonical(&local);
+        let observed: Vec<SocketAddr> = observed
+            .iter()
+            .map(canonical)
+            .filter(|addr| addr.is_ipv4() == local.is_ipv4())
+            .collect();
+
+        let Some(first) = observed.first()
+        else {
+            return Self::Unknown;
+        };
+
+        if observed.iter().all(|addr| *addr == local) {
+            return Self::Open;
+        }
+
+        if observed.len() < 2 {
+            return Self::Unknown;
+        }
+
+        if observed.iter().all(|addr| addr == first) {
+            return Self::EndpointIndependent;
+        }
+
+        if observed.iter().any(|addr| addr.ip() != first.ip()) {
+            return Self::Symmetric;
+        }
+
+        let deltas: Vec<i32> = observed
+            .windows(2)
+            .map(|pair| pair[1].port() as i32 - pair[0].port() as i32)
+            .collect();
+
+        let delta = deltas[0];
+        if delta.abs() <= MAX_PREDICTABLE_DELTA
+            && deltas.iter().all(|d| *d == delta)
+        {
+            Self::Sequential { delta }
+        }
+        else {
+            Self::Symmetric
+        }
+    }
+
+    pub fn strategy(self) -> PunchStrategy {
+        match self {
+            Self::Open | Self::EndpointIndependent | Self::Unknown => {
+                PunchStrategy::Direct
+            }
+            Self::Sequential { .. } => PunchStrategy::PortPrediction,
+            Self::Symmetric => PunchStrategy::Relay,
+        }
+    }
+}
+
+/// What the relays told us about our public address
+#[derive(Clone, Debug)]
+pub struct NatReport {
+    pub local: SocketAddr,
+    pub observed: Vec<SocketAddr>,
+    pub nat: NatType,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn classify() {
+        let local = "192.168.1.2:5000";
+        let cases: &[(&str, &[&str], NatType)] = &[
+            (local, &[], NatType::Unknown),
+            (local, &["192.168.1.2:5000"], NatType::Open),
+            (
+                local,
+                &["192.168.1.2:5000", "192.168.1.2:5000"],
+                NatType::Open,
+            ),
+            (local, &["203.0.113.7:6000"], NatType::Unknown),
+            (
+                local,
+                &["203.0.113.7:6000", "203.0.113.7:6000", "203.0.113.7:6000"],
+                NatType::EndpointIndependent,
+            ),
+            (
+                local,
+                &["203.0.113.7:6000", "203.0.113.7:6001", "203.0.113.7:6002"],
+                NatType::Sequential { delta: 1 },
+            ),
+            (
+                local,
+                &["203.0.113.7:6004", "203.0.113.7:6002"],
+                NatType::Sequential { delta: -2 },
+            ),
+            (
+                local,
+                &["203.0.113.7:6000", "203.0.113.7:6001", "203.0.113.7:6003"],
+                NatType::Symmetric,
+            ),
+            (
+                local,
+                &["203.0.113.7:6000", "203.0.113.7:9000"],
+                NatType::Symmetric,
+            ),
+            (
+                local,
+                &["203.0.113.7:6000", "203.0.113.8:6000"],
+                NatType::Symmetric,
+            ),
+            // An IPv6 relay sees our IPv6 address, which says nothing
+            // about the IPv4 NAT
+            (
+                local,
+                &["203.0.113.7:6000", "[2001:db8::2]:5000", "203.0.113.7:6000"],
+                NatType::EndpointIndependent,
+            ),
+            (
+                local,
+                &["[2001:db8::2]:5000", "192.168.1.2:5000"],
+                NatType::Open,
+            ),
+            (
+                "[2001:db8::2]:5000",
+                &["203.0.113.7:6000", "[2001:db8::2]:5000"],
+                NatType::Open,
+            ),
+            // Dual-stack sockets report IPv4 addresses mapped into IPv6
+            (
+                "[::ffff:192.168.1.2]:5000",
+                &["203.0.113.7:6000", "[::ffff:203.0.113.7]:6000"],
+                NatType::EndpointIndependent,
+            ),
+        ];
+
+        for (local, observed, expected) in cases {
+            let nat = NatType::classify(local.parse().unwrap(), &addrs(observed));
+            assert_eq!(nat, *expected, "{local} seen as {observed:?}");
+        }
+    }
+
+    #[test]
+    fn strategy() {
+        assert_eq!(NatType::Open.strategy(), PunchStrategy::Direct);
+        assert_eq!(NatType::Unknown.strategy(), PunchStrategy::Direct);
+        assert_eq!(
+            NatType::Sequential { delta: 1 }.strategy(),
+            PunchStrategy::PortPrediction
+        );
+        assert_eq!(NatType::Symmetric.strategy(), PunchStrategy::Relay);
+    }
+}
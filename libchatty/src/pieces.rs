@@ -0,0 +1,202 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::system::Hash;
+
+// Files are split into pieces that line up with complete BLAKE3 subtrees.
+// This lets every piece be verified on its own against the file hash.
+pub const PIECE_LEN: u64 = 1 << 20;
+
+const CHUNKS_PER_PIECE: u64 = PIECE_LEN / CHUNK_LEN as u64;
+
+pub fn piece_count(size: u64) -> u64 {
+    size.div_ceil(PIECE_LEN).max(1)
+}
+
+/// Returns the offset and length of a given piece
+pub fn piece_range(size: u64, index: u64) -> (u64, u64) {
+    let offset = index * PIECE_LEN;
+    let len = size.saturating_sub(offset).min(PIECE_LEN);
+    (offset, len)
+}
+
+// Size of the left subtree for a node with `count` children
+fn left_len(count: usize) -> usize {
+    1 << (usize::BITS - 1 - (count - 1).leading_zeros())
+}
+
+fn merge(cvs: &[Hash], is_root: bool) -> Hash {
+    if cvs.len() == 1 {
+        return cvs[0];
+    }
+
+    let (left, right) = cvs.split_at(left_len(cvs.len()));
+    parent_cv(&merge(left, false), &merge(right, false), is_root)
+}
+
+/// Computes the chaining value of a piece. A file that consists of only one
+/// piece is its own root, so the piece hash equals the file hash.
+pub fn hash_piece(size: u64, index: u64, data: &[u8]) -> Hash {
+    let is_root = piece_count(size) == 1;
+    let first_chunk = index * CHUNKS_PER_PIECE;
+
+    if data.len() <= CHUNK_LEN {
+        return ChunkState::new(first_chunk).update(data).finalize(is_root);
+    }
+
+    let cvs: Vec<Hash> = data
+        .chunks(CHUNK_LEN)
+        .zip(first_chunk..)
+        .map(|(chunk, counter)| {
+            ChunkState::new(counter).update(chunk).finalize(false)
+        })
+        .collect();
+
+    merge(&cvs, is_root)
+}
+
+/// Checks whether a list of piece hashes adds up to the given file hash
+pub fn verify_pieces(size: u64, hash: &Hash, pieces: &[Hash]) -> bool {
+    pieces.len() as u64 == piece_count(size) && merge(pieces, true) == *hash
+}
+
+pub async fn read_piece(
+    file: &mut File,
+    size: u64,
+    index: u64,
+) -> io::Result<Vec<u8>> {
+    let (offset, len) = piece_range(size, index);
+    let mut data = vec![0; len as usize];
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut data).await?;
+
+    Ok(data)
+}
+
+/// Hashes every piece of a file. This reads the whole file, so it blocks
+/// and belongs on a blocking thread.
+pub fn hash_file_pieces(
+    file: &mut (impl Read + Seek),
+    size: u64,
+) -> io::Result<Vec<Hash>> {
+    let mut pieces = Vec::new();
+    let mut data = Vec::new();
+
+    file.seek(SeekFrom::Start(0))?;
+    for index in 0..piece_count(size) {
+        let (_, len) = piece_range(size, index);
+        data.resize(len as usize, 0);
+        file.read_exact(&mut data)?;
+        pieces.push(hash_piece(size, index, &data));
+    }
+
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn piece_hashes(data: &[u8]) -> Vec<Hash> {
+        let size = data.len() as u64;
+        (0..piece_count(size))
+            .map(|index| {
+                let (offset, len) = piece_range(size, index);
+                let piece = &data[offset as usize..(offset + len) as usize];
+                hash_piece(size, index, piece)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn last_piece_is_shorter() {
+        let size = 2 * PIECE_LEN + 1234;
+        assert_eq!(piece_count(size), 3);
+        assert_eq!(piece_range(size, 0), (0, PIECE_LEN));
+        assert_eq!(piece_range(size, 2), (2 * PIECE_LEN, 1234));
+    }
+
+    #[test]
+    fn pieces_of_exact_multiple() {
+        let size = 3 * PIECE_LEN;
+        assert_eq!(piece_count(size), 3);
+        assert_eq!(piece_range(size, 2), (2 * PIECE_LEN, PIECE_LEN));
+    }
+
+    #[test]
+    fn empty_file_has_one_piece() {
+        assert_eq!(piece_count(0), 1);
+        assert_eq!(piece_range(0, 0), (0, 0));
+
+        let hashes = piece_hashes(&[]);
+        assert_eq!(hashes, vec![blake3::hash(&[])]);
+        assert!(verify_pieces(0, &blake3::hash(&[]), &hashes));
+    }
+
+    #[test]
+    fn single_piece_is_the_file_hash() {
+        for size in [1, CHUNK_LEN as u64, CHUNK_LEN as u64 + 1, PIECE_LEN] {
+            let data = file(size);
+            assert_eq!(hash_piece(size, 0, &data), blake3::hash(&data));
+        }
+    }
+
+    #[test]
+    fn pieces_add_up_to_the_file_hash() {
+        for size in [PIECE_LEN + 1, 2 * PIECE_LEN, 5 * PIECE_LEN + 4097] {
+            let data = file(size);
+            let hashes = piece_hashes(&data);
+            assert!(verify_pieces(size, &blake3::hash(&data), &hashes));
+        }
+    }
+
+    #[test]
+    fn tampered_piece_is_detected() {
+        let size = 3 * PIECE_LEN + 100;
+        let data = file(size);
+        let hashes = piece_hashes(&data);
+
+        let mut piece =
+            data[PIECE_LEN as usize..2 * PIECE_LEN as usize].to_vec();
+        piece[42] ^= 1;
+        assert_ne!(hash_piece(size, 1, &piece), hashes[1]);
+
+        // A piece moved to another position doesn't match either
+        assert_ne!(hash_piece(size, 2, &data[..PIECE_LEN as usize]), hashes[2]);
+    }
+
+    #[test]
+    fn file_pieces_match() {
+        let size = 2 * PIECE_LEN + 4097;
+        let data = file(size);
+        let mut reader = io::Cursor::new(&data);
+
+        let hashes = hash_file_pieces(&mut reader, size).unwrap();
+        assert_eq!(hashes, piece_hashes(&data));
+    }
+
+    #[test]
+    fn wrong_piece_list_is_rejected() {
+        let size = 2 * PIECE_LEN + 1;
+        let data = file(size);
+        let hash = blake3::hash(&data);
+        let hashes = piece_hashes(&data);
+
+        assert!(!verify_pieces(size, &hash, &hashes[..2]));
+        assert!(!verify_pieces(size + PIECE_LEN, &hash, &hashes));
+
+        let mut swapped = hashes.clone();
+        swapped.swap(0, 1);
+        assert!(!verify_pieces(size, &hash, &swapped));
+    }
+}
@@ -0,0 +1,182 @@
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{mime::Mime, system::FileMetadata};
+
+/// Decides which incoming files are downloaded without asking the user.
+/// Per-contact rules (keyed by the contact's public key) override the
+/// global ones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AutoDownloadPolicy {
+    pub enabled: bool,
+    /// Accepted MIME types, e.g. "image/*" or "application/pdf"
+    pub mime_types: Vec<String>,
+    pub max_size: Option<u64>,
+    /// Only accept files from contacts marked with `/verify`
+    pub verified_only: bool,
+    /// Maximum total size of the downloaded files kept by Aluminum
+    pub quota: Option<u64>,
+    #[serde(with = "crate::base64_codec::keys")]
+    pub contacts: HashMap<VerifyingKey, DownloadRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DownloadRule {
+    pub enabled: Option<bool>,
+    pub mime_types: Option<Vec<String>>,
+    pub max_size: Option<u64>,
+}
+
+impl Default for AutoDownloadPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mime_types: vec![String::from("image/*")],
+            max_size: Some(10_000_000),
+            verified_only: true,
+            quota: None,
+            contacts: HashMap::new(),
+        }
+    }
+}
+
+impl AutoDownloadPolicy {
+    /// Checks whether a file can be downloaded automatically. `verified`
+    /// tells whether the sender is a contact whose key was verified and
+    /// `used` is the total size of the downloaded files that are already
+    /// kept.
+    pub fn allows(
+        &self,
+        from: &VerifyingKey,
+        verified: bool,
+        file: &FileMetadata,
+        used: u64,
+    ) -> bool {
+        if self.verified_only && !verified {
+            return false;
+        }
+
+        let rule = self.contacts.get(from).cloned().unwrap_or_default();
+
+        let enabled = rule.enabled.unwrap_or(self.enabled);
+        let mime_types = rule.mime_types.as_ref().unwrap_or(&self.mime_types);
+        let max_size = rule.max_size.or(self.max_size);
+
+        let type_allowed = file.filetype.as_ref().is_some_and(|filetype| {
+            mime_types
+                .iter()
+                .any(|pattern| mime_matches(pattern, filetype))
+        });
+
+        let size_allowed = max_size.is_none_or(|max| file.size <= max);
+        let quota_allowed = self
+            .quota
+            .is_none_or(|quota| used.saturating_add(file.size) <= quota);
+
+        enabled && type_allowed && size_allowed && quota_allowed
+    }
+}
+
+fn mime_matches(pattern: &str, mime: &Mime) -> bool {
+    match pattern.split_once('/') {
+        Some(("*", "*")) => true,
+        Some((type_, "*")) => type_ == mime.type_().as_str(),
+        _ => pattern == mime.essence_str(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base64::prelude::*;
+    use ed25519_dalek::SigningKey;
+    use std::str::FromStr;
+
+    fn contact(seed: u8) -> VerifyingKey {
+        SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    fn file(filetype: &str, size: u64) -> FileMetadata {
+        FileMetadata {
+            name: String::from("file"),
+            size,
+            hash: blake3::hash(b"file"),
+            filetype: Some(Mime::from_str(filetype).unwrap()),
+        }
+    }
+
+    #[test]
+    fn verified_only() {
+        let policy = AutoDownloadPolicy::default();
+        let alice = contact(1);
+        let image = file("image/png", 1000);
+
+        assert!(policy.allows(&alice, true, &image, 0));
+        assert!(!policy.allows(&alice, false, &image, 0));
+
+        let policy = AutoDownloadPolicy {
+            verified_only: false,
+            ..Default::default()
+        };
+        assert!(policy.allows(&alice, false, &image, 0));
+    }
+
+    #[test]
+    fn limits() {
+        let policy = AutoDownloadPolicy {
+            quota: Some(5000),
+            ..Default::default()
+        };
+        let alice = contact(1);
+
+        assert!(!policy.allows(&alice, true, &file("application/pdf", 1000), 0));
+        assert!(!policy.allows(&alice, true, &file("image/png", 20_000_000), 0));
+        assert!(policy.allows(&alice, true, &file("image/png", 1000), 4000));
+        assert!(!policy.allows(&alice, true, &file("image/png", 1000), 4001));
+    }
+
+    #[test]
+    fn contact_rules() {
+        let mut policy = AutoDownloadPolicy::default();
+        policy.contacts.insert(
+            contact(1),
+            DownloadRule {
+                mime_types: Some(vec![String::from("application/pdf")]),
+                ..Default::default()
+            },
+        );
+        policy.contacts.insert(
+            contact(2),
+            DownloadRule {
+                enabled: Some(false),
+                ..Default::default()
+            },
+        );
+
+        let pdf = file("application/pdf", 1000);
+        let image = file("image/png", 1000);
+
+        assert!(policy.allows(&contact(1), true, &pdf, 0));
+        assert!(!policy.allows(&contact(1), true, &image, 0));
+        assert!(!policy.allows(&contact(2), true, &image, 0));
+        assert!(policy.allows(&contact(3), true, &image, 0));
+    }
+
+    #[test]
+    fn contact_rules_keyed_by_base64() {
+        let key = BASE64_STANDARD.encode(contact(1).as_bytes());
+        let settings = format!(
+            "[contacts.\"{key}\"]\nmime_types = [\"application/pdf\"]\n"
+        );
+
+        let policy: AutoDownloadPolicy = toml::from_str(&settings).unwrap();
+        assert!(policy.contacts.contains_key(&contact(1)));
+
+        let saved = toml::to_string(&policy).unwrap();
+        assert!(saved.contains(&key));
+    }
+}
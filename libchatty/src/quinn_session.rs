@@ -1,92 +1,311 @@
-use std::{error::Error, sync::Arc, time::Duration};
+use std::{
+    error::Error,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
 
-use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, ServerConfig};
-use rustls::pki_types::{
-    CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout,
+    ServerConfig, TokioRuntime, TransportConfig,
+};
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        AlwaysResolvesClientRawPublicKeys,
+    },
+    crypto::{
+        ring::{default_provider, sign::any_eddsa_type},
+        verify_tls13_signature_with_raw_key,
+    },
+    pki_types::{
+        CertificateDer, PrivatePkcs8KeyDer, ServerName,
+        SubjectPublicKeyInfoDer, UnixTime,
+    },
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        AlwaysResolvesServerRawPublicKeys,
+    },
+    sign::CertifiedKey,
+    CertificateError, DigitallySignedStruct, DistinguishedName,
+    PeerIncompatible, SignatureScheme,
 };
+use socket2::{Domain, Protocol, Socket, Type};
 
-// Implementation of `ServerCertVerifier` that verifies everything as trustworthy.
-#[derive(Debug)]
-struct SkipServerVerification(Arc<rustls::crypto::CryptoProvider>);
+// DER encodings of an Ed25519 PKCS #8 v1 private key and of an Ed25519
+// SubjectPublicKeyInfo, both end with the 32 key bytes
+const PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70,
+    0x04, 0x22, 0x04, 0x20,
+];
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
 
-impl SkipServerVerification {
-    fn new() -> Arc<Self> {
-        Arc::new(Self(Arc::new(rustls::crypto::ring::default_provider())))
-    }
+fn identity_spki(key: &VerifyingKey) -> Vec<u8> {
+    [&SPKI_PREFIX[..], key.as_bytes()].concat()
+}
+
+fn parse_spki(spki: &[u8]) -> Option<VerifyingKey> {
+    let key = spki.strip_prefix(&SPKI_PREFIX[..])?;
+    VerifyingKey::from_bytes(key.try_into().ok()?).ok()
+}
+
+// Both sides of a connection present their identity key as an RFC 7250
+// raw public key instead of a certificate
+fn certified_key(identity: &SigningKey) -> Result<Arc<CertifiedKey>, rustls::Error> {
+    let pkcs8 = [&PKCS8_PREFIX[..], identity.as_bytes()].concat();
+    let key = any_eddsa_type(&PrivatePkcs8KeyDer::from(pkcs8))?;
+    let spki = identity_spki(&identity.verifying_key());
+
+    Ok(Arc::new(CertifiedKey::new(vec![CertificateDer::from(spki)], key)))
+}
+
+fn verify_signature(
+    message: &[u8],
+    spki: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, rustls::Error> {
+    verify_tls13_signature_with_raw_key(
+        message,
+        &SubjectPublicKeyInfoDer::from(spki.as_ref()),
+        dss,
+        &default_provider().signature_verification_algorithms,
+    )
 }
 
-impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
+// Accepts a server only if it proves it owns the expected identity
+#[derive(Debug)]
+struct ServerIdentityVerifier(VerifyingKey);
+
+impl ServerCertVerifier for ServerIdentityVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &CertificateDer<'_>,
+        end_entity: &CertificateDer<'_>,
         _intermediates: &[CertificateDer<'_>],
         _server_name: &ServerName<'_>,
         _ocsp: &[u8],
         _now: UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if parse_spki(end_entity) == Some(self.0) {
+            Ok(ServerCertVerified::assertion())
+        }
+        else {
+            Err(CertificateError::ApplicationVerificationFailure.into())
+        }
     }
 
     fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(PeerIncompatible::Tls12NotOffered.into())
+    }
+
+    fn verify_tls13_signature(
         &self,
         message: &[u8],
         cert: &CertificateDer<'_>,
-        dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
-    {
-        rustls::crypto::verify_tls12_signature(
-            message,
-            cert,
-            dss,
-            &self.0.signature_verification_algorithms,
-        )
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+
+    fn requires_raw_public_keys(&self) -> bool {
+        true
+    }
+}
+
+// Accepts any client with an Ed25519 identity, and only those. A server
+// can't know who is connecting, so the identity is checked afterwards with
+// `peer_identity`.
+#[derive(Debug)]
+struct ClientIdentityVerifier;
+
+impl ClientCertVerifier for ClientIdentityVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        true
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        match parse_spki(end_entity) {
+            Some(_) => Ok(ClientCertVerified::assertion()),
+            None => Err(CertificateError::BadEncoding.into()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(PeerIncompatible::Tls12NotOffered.into())
     }
 
     fn verify_tls13_signature(
         &self,
         message: &[u8],
         cert: &CertificateDer<'_>,
-        dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
-    {
-        rustls::crypto::verify_tls13_signature(
-            message,
-            cert,
-            dss,
-            &self.0.signature_verification_algorithms,
-        )
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
     }
 
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        self.0.signature_verification_algorithms.supported_schemes()
+    fn requires_raw_public_keys(&self) -> bool {
+        true
     }
 }
 
-pub fn configure_client() -> ClientConfig {
+/// Configures a connection to the owner of the `server` identity. Anybody
+/// else fails the handshake.
+pub fn configure_client(
+    identity: &SigningKey,
+    server: &VerifyingKey,
+) -> ClientConfig {
     let crypto = rustls::ClientConfig::builder()
         .dangerous()
-        .with_custom_certificate_verifier(SkipServerVerification::new())
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(Arc::new(ServerIdentityVerifier(
+            *server,
+        )))
+        .with_client_cert_resolver(Arc::new(
+            AlwaysResolvesClientRawPublicKeys::new(
+                certified_key(identity).unwrap(),
+            ),
+        ));
+
+    // Besides keeping NAT mappings open, keep-alives move the connection
+    // to the new path soon after our address changes
+    let mut transport_config = TransportConfig::default();
+    transport_config.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport_config.max_idle_timeout(Some(IdleTimeout::try_from(IDLE_TIMEOUT).unwrap()));
 
-    ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()))
+    let mut config = ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(crypto).unwrap(),
+    ));
+    config.transport_config(Arc::new(transport_config));
+    config
 }
 
-pub fn configure_server() -> Result<
-    (ServerConfig, CertificateDer<'static>),
-    Box<dyn Error + Send + Sync + 'static>,
-> {
-    let cert =
-        rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-    let cert_der = CertificateDer::from(cert.cert);
-    let priv_key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
-
-    let mut server_config = ServerConfig::with_single_cert(
-        vec![cert_der.clone()],
-        priv_key.into(),
-    )?;
+/// Returns the identity that the other side of a connection proved to own
+pub fn peer_identity(conn: &Connection) -> Option<VerifyingKey> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+
+    parse_spki(certs.first()?)
+}
+
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Connections that stay silent for this long are closed. Both sides send
+/// keep-alives, so only a dead peer is ever silent for that long.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+pub fn configure_server(
+    identity: &SigningKey,
+) -> Result<ServerConfig, Box<dyn Error + Send + Sync + 'static>> {
+    configure_server_with(identity, KEEP_ALIVE_INTERVAL, Some(IDLE_TIMEOUT))
+}
+
+pub fn configure_server_with(
+    identity: &SigningKey,
+    keep_alive: Duration,
+    idle_timeout: Option<Duration>,
+) -> Result<ServerConfig, Box<dyn Error + Send + Sync + 'static>> {
+    let crypto = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(ClientIdentityVerifier))
+        .with_cert_resolver(Arc::new(AlwaysResolvesServerRawPublicKeys::new(
+            certified_key(identity)?,
+        )));
+
+    let mut server_config =
+        ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
     let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    transport_config.keep_alive_interval(Some(Duration::from_secs(20)));
+    transport_config.keep_alive_interval(Some(keep_alive));
     transport_config.max_concurrent_uni_streams(0_u8.into());
 
-    Ok((server_config, cert_der))
+    if let Some(idle_timeout) = idle_timeout {
+        transport_config.max_idle_timeout(Some(IdleTimeout::try_from(idle_timeout)?));
+    }
+
+    Ok(server_config)
+}
+
+/// Binds a UDP socket. IPv6 sockets only handle IPv6 traffic, so they can
+/// share a port with an IPv4 socket.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// Binds a socket that handles both IPv4 and IPv6 traffic. Hosts without
+/// IPv6 get an IPv4 socket instead.
+pub fn bind_dual_stack(port: u16) -> io::Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let dual_stack = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+        .and_then(|socket| {
+            socket.set_only_v6(false)?;
+            socket.bind(&addr.into())?;
+            Ok(socket)
+        });
+
+    match dual_stack {
+        Ok(socket) => Ok(socket.into()),
+        Err(_) => bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))),
+    }
+}
+
+pub fn make_dual_stack_endpoint(
+    server_config: ServerConfig,
+    port: u16,
+) -> io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        bind_dual_stack(port)?,
+        Arc::new(TokioRuntime),
+    )
+}
+
+pub fn make_endpoint(
+    server_config: ServerConfig,
+    addr: SocketAddr,
+) -> io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        bind_udp(addr)?,
+        Arc::new(TokioRuntime),
+    )
 }
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+use crate::{compression::Compression, policy::AutoDownloadPolicy};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Keeps a private copy of every shared and received file in the
+    /// content-addressed store
+    pub content_store: bool,
+    /// Compression requested for incoming file transfers
+    pub compression: Compression,
+    pub auto_download: AutoDownloadPolicy,
+    /// UDP port that peers connect to, any free port if 0. A fixed port
+    /// can be forwarded so that contacts can connect directly.
+    pub listen_port: u16,
+    pub bandwidth: BandwidthLimits,
+}
+
+/// File transfer rates in KiB/s, 0 means unlimited. Chat messages are never
+/// limited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct BandwidthLimits {
+    /// All uploads together
+    pub upload: u64,
+    /// All downloads together
+    pub download: u64,
+    /// Each file uploaded to a single peer
+    pub transfer_upload: u64,
+    /// Each file downloaded
+    pub transfer_download: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            content_store: false,
+            compression: Compression::Zstd,
+            auto_download: AutoDownloadPolicy::default(),
+            listen_port: 0,
+            bandwidth: BandwidthLimits::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let settings = fs::read_to_string(path)?;
+        toml::from_str::<Settings>(&settings)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) {
+        let serialized = toml::to_string(self).unwrap();
+        fs::write(path, serialized).unwrap();
+    }
+
+    /// Loads the settings file, creating it with the defaults if it's missing
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        }
+        else {
+            let settings = Self::default();
+            settings.save(path);
+            Ok(settings)
+        }
+    }
+}
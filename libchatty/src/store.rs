@@ -0,0 +1,150 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::fs;
+
+use crate::{
+    system::{FileHandle, Hash},
+    utils,
+};
+
+const TEMP_PREFIX: &str = ".incoming-";
+// Partial copies younger than this may still be written to
+const TEMP_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// A content-addressed file store. Every file is kept under the hex
+/// representation of its blake3 hash, so a stored file always matches
+/// the hash it's advertised with and identical files are kept only once.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub async fn open(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    pub fn get_path(&self, hash: &Hash) -> PathBuf {
+        self.root.join(hash.to_hex().as_str())
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.get_path(hash).exists()
+    }
+
+    /// Copies a file into the store and returns a handle pointing at the
+    /// stored copy. Files that are already stored aren't copied again.
+    pub async fn insert(&self, handle: &FileHandle) -> io::Result<FileHandle> {
+        let hash = handle.get_metadata().hash;
+        let path = self.get_path(&hash);
+
+        if !path.exists() {
+            let temp = self.root.join(format!("{TEMP_PREFIX}{}", hash.to_hex()));
+            fs::copy(handle.get_path(), &temp).await?;
+
+            // The source may have changed since its metadata was computed
+            if utils::get_hash_from_path(&temp).await? != hash {
+                let _ = fs::remove_file(&temp).await;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file changed before it could be stored",
+                ));
+            }
+
+            fs::rename(&temp, &path).await?;
+        }
+
+        Ok(handle.with_path(path))
+    }
+
+    /// Removes every stored file that isn't in the `live` set along with
+    /// partial copies left over from earlier runs. Copies that are still
+    /// being written are kept. Returns the number of bytes freed.
+    pub async fn collect_garbage(&self, live: &HashSet<Hash>) -> io::Result<u64> {
+        let mut freed = 0;
+        let mut entries = fs::read_dir(&self.root).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            let metadata = entry.metadata().await?;
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+
+            let is_live = if name.starts_with(TEMP_PREFIX) {
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .unwrap_or_default();
+
+                age < TEMP_GRACE
+            }
+            else {
+                Hash::from_hex(name).is_ok_and(|hash| live.contains(&hash))
+            };
+
+            if !is_live {
+                freed += metadata.len();
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+
+        Ok(freed)
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    #[tokio::test]
+    async fn garbage_collection_keeps_fresh_copies() {
+        let root = std::env::temp_dir()
+            .join(format!("aluminum-store-{}", std::process::id()));
+        let store = FileStore::open(root.clone()).await.unwrap();
+
+        let kept = blake3::hash(b"kept");
+        let dropped = blake3::hash(b"dropped");
+        std::fs::write(store.get_path(&kept), b"kept").unwrap();
+        std::fs::write(store.get_path(&dropped), b"dropped").unwrap();
+
+        let fresh = root.join(format!("{TEMP_PREFIX}fresh"));
+        let stale = root.join(format!("{TEMP_PREFIX}stale"));
+        std::fs::write(&fresh, b"fresh").unwrap();
+        std::fs::write(&stale, b"stale").unwrap();
+        File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * TEMP_GRACE)
+            .unwrap();
+
+        let freed = store
+            .collect_garbage(&HashSet::from([kept]))
+            .await
+            .unwrap();
+
+        assert_eq!(freed, 12);
+        assert!(store.contains(&kept));
+        assert!(!store.contains(&dropped));
+        assert!(fresh.exists());
+        assert!(!stale.exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
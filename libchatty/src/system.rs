@@ -26,6 +26,16 @@ pub fn get_relay_path() -> PathBuf {
         .join("relay.toml")
 }
 
+pub fn get_settings_path() -> PathBuf {
+    get_user_dir()
+        .join("settings.toml")
+}
+
+pub fn get_store_dir() -> PathBuf {
+    get_user_dir()
+        .join("store")
+}
+
 pub fn get_downloads_dir() -> PathBuf {
     dirs::download_dir().unwrap()
 }
@@ -101,6 +111,13 @@ impl FileHandle {
     pub fn get_path(&self) -> &Path {
         &self.path.as_ref()
     }
+
+    pub(crate) fn with_path(&self, path: PathBuf) -> FileHandle {
+        FileHandle {
+            path,
+            metadata: self.metadata.clone()
+        }
+    }
 }
 
 pub type Hash = blake3::Hash;
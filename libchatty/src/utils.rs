@@ -20,6 +20,16 @@ pub fn ed25519_to_noise(key: &SigningKey) -> Keypair {
     }
 }
 
+pub type PresenceToken = [u8; 32];
+
+/// Derives a token that only two contacts can compute, from an X25519 key
+/// exchange between them. The relay uses it to reveal someone's presence
+/// only to their contacts.
+pub fn presence_token(me: &SigningKey, contact: &VerifyingKey) -> PresenceToken {
+    let shared = contact.to_montgomery().mul_clamped(me.to_scalar_bytes());
+    blake3::derive_key("aluminum 2024 presence token", shared.as_bytes())
+}
+
 pub async fn get_hash_from_path(path: &Path) -> io::Result<blake3::Hash> {
     let mut file = File::open(path).await?;
     get_hash_from_file(&mut file).await
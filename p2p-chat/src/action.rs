@@ -1,9 +1,10 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
-use crate::tui::TuiAction;
+use crate::{bandwidth::Direction, peermanager::PeerState, tui::TuiAction};
 use libchatty::{
     messaging::{PeerMessageData, UserMessage},
-    system::FileMetadata
+    nat::NatReport,
+    system::{FileMetadata, Hash}
 };
 use ed25519_dalek::VerifyingKey;
 
@@ -13,12 +14,28 @@ pub enum AppAction {
     TuiAction(TuiAction),
     SelectUser(VerifyingKey),
     ReceiveMessage(UserMessage),
-    DownloadFile,
-    ReceiveDownloadedFile,
+    DownloadFile(VerifyingKey, Hash),
+    ReceiveDownloadedFile(Hash),
+    FailDownload(Hash, String),
+    FailPeerConnection(VerifyingKey, String),
+    ConnectDirectly(VerifyingKey, SocketAddr),
+    ForgetAddress(VerifyingKey),
+    VerifyContact(VerifyingKey, bool),
+    SetBandwidthLimit {
+        direction: Direction,
+        rate: u64,
+        per_transfer: bool,
+    },
     ParseCommand(String),
     SendPeerMessage(PeerMessageData, VerifyingKey),
     SendTextMessage(String),
     ShareFile(PathBuf),
+    ListFiles,
+    CollectGarbage,
+    SetPeerState(VerifyingKey, PeerState),
+    SetPeerRtt(VerifyingKey, Duration),
+    SetPresence(VerifyingKey, bool),
+    SetNatReport(NatReport),
     SetOffline,
     SetConnecting,
     SetConnected,
@@ -0,0 +1,338 @@
+use libchatty::settings::BandwidthLimits;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use strum_macros::Display;
+use tokio::time::{sleep, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Display)]
+pub enum Direction {
+    #[strum(to_string = "upload")]
+    Upload,
+    #[strum(to_string = "download")]
+    Download,
+}
+
+/// Holds up to a second's worth of bytes. A packet may overdraw the bucket,
+/// the next one then waits until it's refilled. Uploads are sent in chunks
+/// small enough that this only happens at the lowest limits.
+#[derive(Debug)]
+pub struct TokenBucket {
+    // Bytes per second, unlimited if 0
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self {
+            rate: 0.0,
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn set_limit(&mut self, kib_per_sec: u64) {
+        let rate = kib_per_sec as f64 * 1024.0;
+        if rate != self.rate {
+            self.refill();
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refill = (now - self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.updated = now;
+    }
+
+    fn delay(&mut self) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        self.refill();
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        }
+        else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn take(&mut self, len: usize) {
+        if self.rate > 0.0 {
+            self.refill();
+            self.tokens -= len as f64;
+        }
+    }
+
+    /// Whether the bucket has been idle for long enough to be full again,
+    /// in which case it can be forgotten
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    limits: BandwidthLimits,
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+/// The rate limits of file transfers, shared by all of them and adjustable
+/// at any time. Every transfer also has a bucket of its own, for the
+/// per-transfer limit.
+#[derive(Clone, Debug)]
+pub struct Bandwidth(Arc<Mutex<Buckets>>);
+
+impl Bandwidth {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        let bandwidth = Self(Arc::new(Mutex::new(Buckets {
+            limits,
+            upload: TokenBucket::new(),
+            download: TokenBucket::new(),
+        })));
+
+        bandwidth.set_limits(limits);
+        bandwidth
+    }
+
+    pub fn set_limits(&self, limits: BandwidthLimits) {
+        let mut buckets = self.0.lock().unwrap();
+        buckets.upload.set_limit(limits.upload);
+        buckets.download.set_limit(limits.download);
+        buckets.limits = limits;
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        self.0.lock().unwrap().limits
+    }
+
+    /// How long a transfer has to wait before it may send, or ask for, more
+    /// data
+    pub fn delay(
+        &self,
+        direction: Direction,
+        transfer: &mut TokenBucket,
+    ) -> Duration {
+        let mut buckets = self.0.lock().unwrap();
+        let limits = buckets.limits;
+
+        let global = match direction {
+            Direction::Upload => {
+                transfer.set_limit(limits.transfer_upload);
+                &mut buckets.upload
+            }
+            Direction::Download => {
+                transfer.set_limit(limits.transfer_download);
+                &mut buckets.download
+            }
+        };
+
+        global.delay().max(transfer.delay())
+    }
+
+    /// Charges a transfer for `len` bytes
+    pub fn take(
+        &self,
+        direction: Direction,
+        transfer: &mut TokenBucket,
+        len: usize,
+    ) {
+        let mut buckets = self.0.lock().unwrap();
+        let global = match direction {
+            Direction::Upload => &mut buckets.upload,
+            Direction::Download => &mut buckets.download,
+        };
+
+        global.take(len);
+        transfer.take(len);
+    }
+
+    /// Waits until a transfer may move `len` more bytes and charges it
+    pub async fn acquire(
+        &self,
+        direction: Direction,
+        transfer: &Mutex<TokenBucket>,
+        len: usize,
+    ) {
+        loop {
+            let delay = self.delay(direction, &mut transfer.lock().unwrap());
+            if delay.is_zero() {
+                break;
+            }
+
+            sleep(delay).await;
+        }
+
+        self.take(direction, &mut transfer.lock().unwrap(), len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::time::advance;
+
+    const KIB: usize = 1024;
+
+    fn limits(upload: u64, transfer_upload: u64) -> BandwidthLimits {
+        BandwidthLimits {
+            upload,
+            transfer_upload,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_never_waits() {
+        let bandwidth = Bandwidth::new(BandwidthLimits::default());
+        let mut transfer = TokenBucket::new();
+
+        bandwidth.take(Direction::Upload, &mut transfer, 100 * KIB);
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut transfer),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overdraw_waits_for_refill() {
+        let bandwidth = Bandwidth::new(limits(16, 0));
+        let mut transfer = TokenBucket::new();
+        let delay = |transfer: &mut TokenBucket| {
+            bandwidth.delay(Direction::Upload, transfer)
+        };
+
+        assert_eq!(delay(&mut transfer), Duration::ZERO);
+
+        // Two seconds' worth at once puts the bucket two seconds behind
+        bandwidth.take(Direction::Upload, &mut transfer, 32 * KIB);
+        assert_eq!(delay(&mut transfer), Duration::from_secs(2));
+
+        advance(Duration::from_millis(500)).await;
+        assert_eq!(delay(&mut transfer), Duration::from_millis(1500));
+
+        advance(Duration::from_millis(1500)).await;
+        assert_eq!(delay(&mut transfer), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_is_capped_at_a_second() {
+        let bandwidth = Bandwidth::new(limits(16, 0));
+        let mut transfer = TokenBucket::new();
+
+        // Idling doesn't allow a burst of more than a second's worth
+        advance(Duration::from_secs(60)).await;
+        bandwidth.take(Direction::Upload, &mut transfer, 48 * KIB);
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut transfer),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfers_share_the_global_limit() {
+        let bandwidth = Bandwidth::new(limits(16, 0));
+        let mut first = TokenBucket::new();
+        let mut second = TokenBucket::new();
+
+        bandwidth.take(Direction::Upload, &mut first, 16 * KIB);
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut second),
+            Duration::from_secs(1)
+        );
+
+        // Downloads have a bucket of their own
+        assert_eq!(
+            bandwidth.delay(Direction::Download, &mut second),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_transfer_limit_is_separate() {
+        let bandwidth = Bandwidth::new(limits(0, 16));
+        let mut first = TokenBucket::new();
+        let mut second = TokenBucket::new();
+
+        // Sets the per-transfer limit of both buckets
+        bandwidth.delay(Direction::Upload, &mut first);
+        bandwidth.delay(Direction::Upload, &mut second);
+
+        bandwidth.take(Direction::Upload, &mut first, 32 * KIB);
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut first),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut second),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stricter_limit_wins() {
+        let bandwidth = Bandwidth::new(limits(32, 16));
+        let mut transfer = TokenBucket::new();
+        bandwidth.delay(Direction::Upload, &mut transfer);
+
+        bandwidth.take(Direction::Upload, &mut transfer, 32 * KIB);
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut transfer),
+            Duration::from_secs(2)
+        );
+
+        bandwidth.set_limits(limits(8, 64));
+        assert_eq!(
+            bandwidth.delay(Direction::Upload, &mut transfer),
+            Duration::from_secs(4)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_paces_transfers() {
+        let bandwidth = Bandwidth::new(limits(16, 0));
+        let transfer = Mutex::new(TokenBucket::new());
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            bandwidth.acquire(Direction::Upload, &transfer, 16 * KIB).await;
+        }
+
+        // The first chunk goes out right away, the others a second apart
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_bucket_is_full() {
+        let bandwidth = Bandwidth::new(limits(0, 16));
+        let mut transfer = TokenBucket::new();
+        bandwidth.delay(Direction::Upload, &mut transfer);
+
+        // Buckets start out empty
+        bandwidth.take(Direction::Upload, &mut transfer, 8 * KIB);
+        advance(Duration::from_secs(1)).await;
+        assert!(!transfer.is_full());
+
+        advance(Duration::from_secs(1)).await;
+        assert!(transfer.is_full());
+    }
+}
@@ -1,101 +1,318 @@
 use libchatty::{
-    messaging::{PeerMessageData, RelayRequest, RelayResponse, UserMessage},
+    compression::Compression,
+    messaging::{
+        ErrorCode, PeerMessageData, RelayRequest, RelayResponse, UserMessage,
+    },
+    ice::{self, Candidate, CandidateKind},
     identity::{Myself, Relay, UserDb},
+    invite::Invite,
+    nat::{NatReport, NatType, PunchStrategy},
     noise_session::*,
     quinn_session::*,
     noise_transport::*,
-    system::FileMetadata,
+    settings::Settings,
+    system::{FileMetadata, Hash},
     utils,
 };
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
-    net::SocketAddr, time::Duration,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant, SystemTime},
     sync::{Arc, Mutex}
 };
 
-use crate::peermanager::{P2pRole, PeerCommand, PeerManagerHandle};
+use crate::{
+    bandwidth::Bandwidth,
+    discovery::LanDiscovery,
+    peermanager::{
+        P2pRole, PeerCommand, PeerContext, PeerManagerHandle, PeerState,
+        PieceHashCache,
+    },
+    swarm::SwarmDownload,
+};
 use ed25519_dalek::VerifyingKey;
-use futures::{sink::SinkExt, stream::StreamExt};
+use futures::{
+    sink::SinkExt,
+    stream::{BoxStream, SelectAll, SplitSink, StreamExt},
+};
 use quinn::{Connection, Endpoint, RecvStream, SendStream};
-use rustls::pki_types::CertificateDer;
 use tokio::{
     io::{Join, AsyncRead, AsyncWrite},
-    sync::mpsc, time::sleep
+    sync::{mpsc, oneshot, watch}, time::{interval, sleep, timeout}
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{event, Level};
 
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// Relays ask the relays they federate with about users they don't know,
+// which takes up to 5 seconds on its own
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+// Relays that keep failing are tried at most this far apart
+const MAX_RELAY_BACKOFF: Duration = Duration::from_secs(60);
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// The wall clock running this much ahead of the monotonic clock, which
+// stops while suspended, means that we've been asleep
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
 type RelayConnection<T> = NoiseTransport<T, RelayRequest, RelayResponse>;
 type QuicRelayConn = RelayConnection<Join<RecvStream, SendStream>>;
 
+// Tells relay sessions apart, even once earlier ones have ended
+type SessionId = u64;
+
+// Responses from all relays, tagged with the id of their session.
+// None marks the end of a session.
+type RelayResponses = SelectAll<
+    BoxStream<'static, (SessionId, Option<io::Result<RelayResponse>>)>,
+>;
+
+// A peer manager asking where its peer can be reached now
+type Lookup = (VerifyingKey, oneshot::Sender<Option<Vec<Candidate>>>);
+
+// A request that a relay answered with an error. Unlike a broken
+// connection, this leaves the relay session intact.
+#[derive(Debug)]
+struct Refusal {
+    code: ErrorCode,
+    message: String,
+}
+
+struct RelaySession {
+    id: SessionId,
+    relay: Relay,
+    conn: Connection,
+    sink: SplitSink<QuicRelayConn, RelayRequest>,
+    // Replies still to come for requests that we gave up on
+    late_replies: u32,
+}
+
 // TODO: Maybe move this to libchatty?
 // Try to make this work for both the p2p clients and the relay server
-#[derive(Debug)]
 struct ConnManager {
     identity: Myself,
-    relay: Relay,
+    // Relays we always register with, sorted by priority
+    relays: Vec<Relay>,
     tx: mpsc::Sender<ConnMessage>,
     rx: mpsc::Receiver<ConnCommand>,
     token: CancellationToken,
     tracker: TaskTracker,
     connections: HashMap<VerifyingKey, PeerManagerHandle>,
-    db: Arc<Mutex<UserDb>>
+    db: Arc<Mutex<UserDb>>,
+    sessions: Vec<RelaySession>,
+    responses: RelayResponses,
+    next_session: SessionId,
+    // When each relay that we lost, or couldn't reach, is tried again and
+    // how long to wait after that
+    relay_retries: HashMap<VerifyingKey, (Instant, Duration)>,
+    // Streams opened by the relays on behalf of other peers
+    tunnel_tx: mpsc::Sender<(RecvStream, SendStream)>,
+    tunnel_rx: mpsc::Receiver<(RecvStream, SendStream)>,
+    // Connection to each relay, used by peers to open relayed tunnels
+    relay_conns: HashMap<VerifyingKey, watch::Sender<Option<Connection>>>,
+    // Sessions through which each contact is seen online
+    presence: HashMap<VerifyingKey, HashSet<SessionId>>,
+    // Decides how peer connections are attempted
+    nat: NatType,
+    // Contacts found on the local network, and where they listen
+    lan_tx: mpsc::Sender<(VerifyingKey, SocketAddr)>,
+    lan_rx: mpsc::Receiver<(VerifyingKey, SocketAddr)>,
+    lan_peers: HashMap<VerifyingKey, SocketAddr>,
+    // Peers connecting to us directly, identified by their certificates
+    incoming_tx: mpsc::Sender<Connection>,
+    incoming_rx: mpsc::Receiver<Connection>,
+    // Peers retrying a connection look their peer up again
+    lookup_tx: mpsc::Sender<Lookup>,
+    lookup_rx: mpsc::Receiver<Lookup>,
+    // Kept across relay reconnections, so that peers can keep using it
+    endpoint: Option<Endpoint>,
+    listen_port: u16,
+    // What the network looked like at the last check
+    local_ip: Option<IpAddr>,
+    last_check: (Instant, SystemTime),
+    // Limits for file transfers, handed to every peer and download
+    bandwidth: Bandwidth,
+    // Shared by every peer, so that a file is only hashed once
+    piece_hashes: PieceHashCache,
 }
 
 pub enum ConnMessage {
     UserMessage(UserMessage),
-    // TODO - change this to DownloadedFile(Hash)
-    DownloadedFile,
+    DownloadedFile(Hash),
+    TransferFailed { hash: Hash, reason: String },
+    PeerUnreachable { peer: VerifyingKey, reason: String },
+    Nat(NatReport),
+    PeerState(VerifyingKey, PeerState),
+    PeerRtt(VerifyingKey, Duration),
+    Presence(VerifyingKey, bool),
     ServerOffline,
     Connecting,
     Connected
 }
 
 fn make_server_endpoint(
-    bind_addr: SocketAddr,
-) -> Result<
-    (Endpoint, CertificateDer<'static>),
-    Box<dyn Error + Send + Sync + 'static>,
-> {
-    let (server_config, server_cert) = configure_server()?;
-    let endpoint = Endpoint::server(server_config, bind_addr)?;
-    Ok((endpoint, server_cert))
+    identity: &Myself,
+    port: u16,
+) -> Result<Endpoint, Box<dyn Error + Send + Sync + 'static>> {
+    let server_config = configure_server(identity.get_private_key())?;
+    let endpoint = make_dual_stack_endpoint(server_config, port)?;
+    Ok(endpoint)
+}
+
+// The address our packets to `to` leave from. The endpoint is bound to the
+// unspecified address, so the interface has to be looked up.
+fn local_address(endpoint: &Endpoint, to: SocketAddr) -> io::Result<SocketAddr> {
+    Ok(SocketAddr::new(route_source(to)?, endpoint.local_addr()?.port()))
+}
+
+// Connecting a UDP socket doesn't send anything, it only picks the route
+fn route_source(to: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind(SocketAddr::new(
+        if to.is_ipv6() {
+            Ipv6Addr::UNSPECIFIED.into()
+        }
+        else {
+            Ipv4Addr::UNSPECIFIED.into()
+        },
+        0,
+    ))?;
+    socket.connect(to)?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
+// Our global IPv6 address, if we have one and the endpoint can use it
+fn ipv6_address(endpoint: &Endpoint) -> Option<SocketAddr> {
+    let local = endpoint.local_addr().ok()?;
+    if local.is_ipv4() {
+        return None;
+    }
+
+    // Any global address picks the route, the documentation prefix will do
+    let probe = SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 9);
+    let IpAddr::V6(ip) = route_source(probe).ok()?
+    else {
+        return None;
+    };
+
+    let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+    if ip.is_loopback() || ip.is_unspecified() || link_local {
+        return None;
+    }
+
+    Some(SocketAddr::new(ip.into(), local.port()))
+}
+
+// The candidates that we tell a relay about. It sees our reflexive address
+// itself, but not the addresses of our interfaces, which are the only way
+// for peers behind the same NAT to reach us without hairpinning.
+fn gather_candidates(endpoint: &Endpoint, relay: &Relay) -> Vec<Candidate> {
+    let host = local_address(endpoint, relay.addr).ok();
+    let mut candidates: Vec<Candidate> = host
+        .into_iter()
+        .chain(ipv6_address(endpoint))
+        .map(|addr| Candidate::new(CandidateKind::Host, addr))
+        .collect();
+
+    candidates.push(Candidate::new(CandidateKind::Relayed, relay.addr));
+    ice::sort_candidates(&mut candidates);
+    candidates
+}
+
+// Responses that answer one of our requests, rather than arriving on
+// their own
+fn is_reply(response: &RelayResponse) -> bool {
+    matches!(
+        response,
+        RelayResponse::UserAddress(_)
+            | RelayResponse::ReflexiveAddress { .. }
+            | RelayResponse::Error { .. }
+    )
 }
 
 impl ConnManager {
     async fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (endpoint, _conn, mut stream) = self.connect().await?;
+        let endpoint = self.connect().await?;
+
+        // Without any relay, peers on the local network can still be
+        // reached while we keep trying
+        let mut relay_retry = interval(RELAY_RETRY_INTERVAL);
+        relay_retry.reset();
+
+        let mut network_check = interval(NETWORK_CHECK_INTERVAL);
+        self.network_changed();
 
         loop {
             tokio::select! {
-                Some(ConnCommand {to, command}) = self.rx.recv() => {
-                    if !self.connections.contains_key(&to) {
-                        stream.send(RelayRequest::GetUser(to)).await?;
-                        
-                        let addr = stream.next().await
-                            .ok_or("Connection ended unexpectedly")??
-                            .as_user_address()
-                            .ok_or("Expected address, received something else")?
-                            .ok_or("Couldn't find a peer.")?;
-
-                        // ^ TODO: Instead of crashing send a message to the UI
-                        // that the peer couldn't be found.
-
-                        event!(Level::INFO, "Trying to connect to: {addr}");
-                        self.register_connection(endpoint.clone(), to, addr, P2pRole::Initiator);
-                    }
+                Some(command) = self.rx.recv() => {
+                    match command {
+                        ConnCommand::Peer { to, command } => {
+                            if let Err(refusal) = self.ensure_connection(&endpoint, to).await {
+                                let reason = refusal.message;
+                                let _ = self.tx.send(ConnMessage::PeerUnreachable { peer: to, reason }).await;
+                                continue;
+                            }
 
-                    self.connections
-                        .get(&to)
-                        .unwrap()
-                        .tx
-                        .send(command)
-                        .await?;
+                            // The peer manager may give up in the meantime
+                            let handle = self.connections.get(&to).unwrap();
+                            if handle.tx.send(command).await.is_err() {
+                                let reason = String::from("The connection was given up");
+                                let _ = self.tx.send(ConnMessage::PeerUnreachable { peer: to, reason }).await;
+                            }
+                        }
+                        ConnCommand::Download { from, file, compression, sources } => {
+                            if let Err(refusal) = self.ensure_connection(&endpoint, from).await {
+                                let reason = refusal.message;
+                                let _ = self.tx.send(ConnMessage::TransferFailed { hash: file.hash, reason }).await;
+                                continue;
+                            }
+
+                            self.download(from, file, compression, &sources);
+                        }
+                        ConnCommand::Connect { to, addr } => {
+                            if self.is_connecting(&to) {
+                                event!(Level::DEBUG, "Already connected to {addr}");
+                                continue;
+                            }
+
+                            self.connect_directly(&endpoint, to, addr);
+                        }
+                    }
+                }
+                Some((id, response)) = self.responses.next(), if !self.sessions.is_empty() => {
+                    match response {
+                        Some(Ok(response)) => {
+                            self.handle_relay_response(&endpoint, id, response).await;
+                        }
+                        _ => self.drop_session(id).await,
+                    }
                 }
-                Some(Ok(RelayResponse::AwaitConnection(pubkey, addr))) = stream.next() => {
-                    self.register_connection(endpoint.clone(), pubkey, addr, P2pRole::Responder);
+                Some((reader, writer)) = self.tunnel_rx.recv() => {
+                    self.accept_tunnel(reader, writer).await;
+                }
+                Some((peer, addr)) = self.lan_rx.recv() => {
+                    self.found_on_lan(&endpoint, peer, addr);
+                }
+                Some(conn) = self.incoming_rx.recv() => {
+                    self.accept_peer(&endpoint, conn);
+                }
+                Some((peer, reply)) = self.lookup_rx.recv() => {
+                    let addr = self.refresh_address(&endpoint, peer).await;
+                    let _ = reply.send(addr);
+                }
+                _ = network_check.tick() => {
+                    if self.network_changed() {
+                        self.migrate().await?;
+                    }
+                }
+                _ = relay_retry.tick(), if self.is_missing_relays() => {
+                    if self.sessions.is_empty() {
+                        let _ = self.tx.send(ConnMessage::Connecting).await;
+                    }
+                    self.connect_relays(&endpoint).await?;
                 }
                 _ = self.token.cancelled() => { break }
                 else => { self.token.cancel(); }
@@ -105,74 +322,891 @@ impl ConnManager {
         Ok(())
     }
 
-    async fn connect(
+    // Whether a peer manager is still trying to reach the peer. One that
+    // gave up is replaced on the next attempt.
+    fn is_connecting(&self, peer: &VerifyingKey) -> bool {
+        self.connections
+            .get(peer)
+            .is_some_and(|handle| handle.is_running())
+    }
+
+    // Finds a peer and starts connecting to it. A peer that can't be found
+    // is reported as a refusal.
+    async fn ensure_connection(
         &mut self,
-    ) -> Result<
-        (Endpoint, Connection, QuicRelayConn),
-        Box<dyn Error + Send + Sync>,
-    > {
-        event!(Level::DEBUG, "Configuring self");
-        let bind_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let (mut endpoint, _server_cert) =
-            make_server_endpoint(bind_addr).unwrap();
+        endpoint: &Endpoint,
+        to: VerifyingKey,
+    ) -> Result<(), Refusal> {
+        if self.is_connecting(&to) {
+            return Ok(());
+        }
+
+        // An address that the user gave us is tried along with whatever
+        // the relays know, and on its own when they don't know the peer
+        let hint = self.db.lock().unwrap().addresses.get(&to).copied();
+
+        match (self.lookup(endpoint, to).await, hint) {
+            (Ok((mut candidates, relay_key)), hint) => {
+                if let Some(addr) = hint {
+                    candidates.push(Candidate::new(CandidateKind::Host, addr));
+                    ice::sort_candidates(&mut candidates);
+                }
+
+                event!(Level::INFO, "Trying to connect to: {candidates:?}");
+                let strategy = self.nat.strategy();
+                self.register_connection(endpoint.clone(), to, candidates, P2pRole::Initiator, Some(relay_key), strategy);
+                Ok(())
+            }
+            (Err(_), Some(addr)) => {
+                self.connect_directly(endpoint, to, addr);
+                Ok(())
+            }
+            (Err(refusal), None) => Err(refusal),
+        }
+    }
+
+    // Where a peer that failed to connect can be reached now. An address
+    // hint that just failed while a relay can find the peer is stale, so
+    // it's forgotten.
+    async fn refresh_address(
+        &mut self,
+        endpoint: &Endpoint,
+        peer: VerifyingKey,
+    ) -> Option<Vec<Candidate>> {
+        let hint = self.db.lock().unwrap().addresses.get(&peer).copied();
+        let lan = self.lan_peers.get(&peer).copied();
+
+        let mut candidates = match self.lookup(endpoint, peer).await {
+            Ok((candidates, _)) => {
+                if let Some(addr) = hint {
+                    event!(Level::INFO, "Forgetting the stale address {addr}");
+                    self.db.lock().unwrap().addresses.remove(&peer);
+                }
+
+                candidates
+            }
+            Err(_) => hint
+                .map(|addr| Candidate::new(CandidateKind::Host, addr))
+                .into_iter()
+                .collect(),
+        };
+
+        if let Some(addr) = lan {
+            candidates.push(Candidate::new(CandidateKind::Host, addr));
+        }
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        ice::sort_candidates(&mut candidates);
+        Some(candidates)
+    }
+
+    // Asks the relays for the candidates of a peer, the ones it advertised
+    // first. Returns the candidates along with the relay that knew them.
+    // Relays that fail to answer are skipped.
+    async fn lookup(
+        &mut self,
+        endpoint: &Endpoint,
+        to: VerifyingKey,
+    ) -> Result<(Vec<Candidate>, VerifyingKey), Refusal> {
+        // Relays advertised by the contact are tried first
+        let mut hints = self
+            .db
+            .lock()
+            .unwrap()
+            .relays
+            .get(&to)
+            .cloned()
+            .unwrap_or_default();
+        hints.sort_by_key(|relay| relay.priority);
+
+        let candidates: Vec<Relay> = hints
+            .iter()
+            .chain(self.relays.iter().filter(|relay| !hints.contains(relay)))
+            .cloned()
+            .collect();
+
+        let mut refusal = Refusal {
+            code: ErrorCode::UnknownUser,
+            message: String::from("Not connected to any of your relays"),
+        };
+
+        for relay in candidates {
+            let id = match self.find_session(&relay) {
+                Some(id) => id,
+                None if hints.contains(&relay) => {
+                    match self.add_session(endpoint, relay.clone()).await {
+                        Ok(id) => id,
+                        Err(e) => {
+                            event!(Level::INFO, "Couldn't connect to {}: {e}", relay.addr);
+                            continue;
+                        }
+                    }
+                }
+                None => continue,
+            };
+
+            match self.get_user(endpoint, id, to).await {
+                Ok(Ok(candidates)) => return Ok((candidates, relay.public_key)),
+                // Any other reason says more than an unknown user
+                Ok(Err(e)) if e.code != ErrorCode::UnknownUser => refusal = e,
+                Ok(Err(_)) => {}
+                Err(e) => {
+                    event!(Level::INFO, "Asking {} for a peer failed: {e}", relay.addr);
+                }
+            }
+        }
+
+        Err(refusal)
+    }
+
+    // Sends a request to a single relay and waits for its reply, for at
+    // most `wait`. Sessions that end in the meantime are dropped, and so is
+    // this one if the request fails. A reply that comes after we've given
+    // up on it is dropped when it arrives.
+    async fn request(
+        &mut self,
+        endpoint: &Endpoint,
+        id: SessionId,
+        request: RelayRequest,
+        wait: Duration,
+    ) -> Result<RelayResponse, Box<dyn Error + Send + Sync>> {
+        let session =
+            self.session_mut(id).ok_or("The relay session has ended")?;
+
+        if let Err(e) = session.sink.send(request).await {
+            self.drop_session(id).await;
+            return Err(e.into());
+        }
+
+        let expired = sleep(wait);
+        tokio::pin!(expired);
+
+        // Other relays, or presence updates, may respond before the reply.
+        // Only waiting is cut short, responses are always handled in full.
+        loop {
+            let next = tokio::select! {
+                next = self.responses.next() => next,
+                _ = &mut expired => {
+                    if let Some(session) = self.session_mut(id) {
+                        session.late_replies += 1;
+                    }
+                    return Err("The relay didn't answer in time".into());
+                }
+            };
+
+            match next {
+                Some((i, Some(Ok(response))))
+                    if i == id
+                        && is_reply(&response)
+                        && !self.owes_late_replies(id) =>
+                {
+                    return Ok(response);
+                }
+                Some((i, Some(Ok(response)))) => {
+                    self.handle_relay_response(endpoint, i, response).await;
+                }
+                Some((i, _)) => {
+                    self.drop_session(i).await;
+                    if i == id {
+                        return Err("Connection ended unexpectedly".into());
+                    }
+                }
+                None => return Err("Connection ended unexpectedly".into()),
+            }
+        }
+    }
+
+    // Asks a single relay for the candidates of a user
+    async fn get_user(
+        &mut self,
+        endpoint: &Endpoint,
+        id: SessionId,
+        to: VerifyingKey,
+    ) -> Result<Result<Vec<Candidate>, Refusal>, Box<dyn Error + Send + Sync>> {
+        let request = RelayRequest::GetUser(to);
+        match self.request(endpoint, id, request, REQUEST_TIMEOUT).await? {
+            RelayResponse::UserAddress(candidates) => Ok(Ok(candidates)),
+            RelayResponse::Error { code, message } => {
+                event!(Level::INFO, "Lookup failed ({code}): {message}");
+                Ok(Err(Refusal { code, message }))
+            }
+            response => Err(format!("Unexpected response: {response:?}").into()),
+        }
+    }
+
+    // Asks every relay, and the probe ports they offer, which address we're
+    // connecting from and works out what kind of NAT we're behind
+    async fn detect_nat(
+        &mut self,
+        endpoint: &Endpoint,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut observed = Vec::new();
+        let mut probes = Vec::new();
+
+        let sessions: Vec<(SessionId, Relay)> = self
+            .sessions
+            .iter()
+            .map(|session| (session.id, session.relay.clone()))
+            .collect();
+
+        // A relay that doesn't answer is left out
+        for (id, relay) in sessions {
+            let request = RelayRequest::GetReflexiveAddress;
+            match self.request(endpoint, id, request, PROBE_TIMEOUT).await {
+                Ok(RelayResponse::ReflexiveAddress { observed: addr, probe_port }) => {
+                    observed.push(addr);
+                    if let Some(port) = probe_port {
+                        probes.push((relay, port));
+                    }
+                }
+                Ok(response) => {
+                    event!(Level::INFO, "Relay didn't tell our address: {response:?}");
+                }
+                Err(e) => event!(Level::INFO, "Asking {} for our address failed: {e}", relay.addr),
+            }
+        }
+
+        // Each probe is a new destination for the NAT
+        for (relay, port) in probes {
+            match timeout(PROBE_TIMEOUT, self.probe(endpoint, &relay, port)).await {
+                Ok(Ok(addr)) => observed.push(addr),
+                Ok(Err(e)) => event!(Level::INFO, "Probing {} failed: {e}", relay.addr),
+                Err(_) => event!(Level::INFO, "Probing {} timed out", relay.addr),
+            }
+        }
+
+        // NATs map every address family on their own. IPv4 is where
+        // they're found, so it decides when we have it.
+        let ipv4 = observed.iter().any(|addr| addr.ip().to_canonical().is_ipv4());
+        let relays = self.sessions.iter().map(|session| session.relay.addr);
+        let Some(relay) = relays
+            .clone()
+            .find(|addr| addr.is_ipv4() == ipv4)
+            .or_else(|| relays.clone().next())
+        else {
+            return Ok(());
+        };
+
+        let local = local_address(endpoint, relay)?;
+        self.nat = NatType::classify(local, &observed);
+        event!(Level::INFO, "NAT type: {}, seen as {observed:?}", self.nat);
+
+        let report = NatReport {
+            local,
+            observed,
+            nat: self.nat,
+        };
+        let _ = self.tx.send(ConnMessage::Nat(report)).await;
+
+        Ok(())
+    }
+
+    // Asks a relay's probe port for our address, without registering
+    async fn probe(
+        &mut self,
+        endpoint: &Endpoint,
+        relay: &Relay,
+        port: u16,
+    ) -> Result<SocketAddr, Box<dyn Error + Send + Sync>> {
+        let addr = SocketAddr::new(relay.addr.ip(), port);
+        let config =
+            configure_client(self.identity.get_private_key(), &relay.public_key);
+        let conn = endpoint.connect_with(config, addr, "localhost")?.await?;
+
+        let (writer, reader) = conn.open_bi().await?;
+        let stream = tokio::io::join(reader, writer);
+        let mut stream = self.upgrade_relay_connection(relay, stream).await?;
+
+        stream.send(RelayRequest::GetReflexiveAddress).await?;
+        let response = stream.next().await.ok_or("Connection closed")??;
+        conn.close(0u32.into(), b"done");
+
+        match response {
+            RelayResponse::ReflexiveAddress { observed, .. } => Ok(observed),
+            response => Err(format!("Unexpected response: {response:?}").into()),
+        }
+    }
+
+    async fn handle_relay_response(
+        &mut self,
+        endpoint: &Endpoint,
+        id: SessionId,
+        response: RelayResponse,
+    ) {
+        let Some(session) = self.session_mut(id)
+        else {
+            return;
+        };
+
+        if is_reply(&response) && session.late_replies > 0 {
+            session.late_replies -= 1;
+            event!(Level::DEBUG, "Dropping a late reply: {response:?}");
+            return;
+        }
+
+        let relay = session.relay.clone();
+        match response {
+            RelayResponse::AwaitConnection(pubkey, candidates) => {
+                let relay_key = Some(relay.public_key);
+                let strategy = self.nat.strategy();
+                self.register_connection(endpoint.clone(), pubkey, candidates, P2pRole::Responder, relay_key, strategy);
+            }
+            RelayResponse::Presence(pubkey, online) => {
+                // A contact may be registered with more than one relay
+                let sessions = self.presence.entry(pubkey).or_default();
+                if online {
+                    sessions.insert(id);
+                }
+                else {
+                    sessions.remove(&id);
+                }
+
+                let online = !sessions.is_empty();
+                let _ = self.tx.send(ConnMessage::Presence(pubkey, online)).await;
+            }
+            RelayResponse::Error { code, message } => {
+                let relay = relay.addr;
+                event!(Level::WARN, "The relay at {relay} reported an error ({code}): {message}");
+            }
+            response => {
+                event!(Level::DEBUG, "Ignoring an unexpected response: {response:?}");
+            }
+        }
+    }
+
+    // Lets our contacts see that we're online and asks to be told when
+    // they are
+    async fn share_presence(
+        &mut self,
+        stream: &mut QuicRelayConn,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let contacts: Vec<VerifyingKey> =
+            self.db.lock().unwrap().remote.keys().copied().collect();
+
+        let tokens: Vec<_> = contacts
+            .iter()
+            .map(|contact| {
+                (*contact, utils::presence_token(&self.identity.private_key, contact))
+            })
+            .collect();
+
+        stream
+            .send(RelayRequest::SetPresenceTokens(
+                tokens.iter().map(|(_, token)| *token).collect(),
+            ))
+            .await?;
+        stream.send(RelayRequest::Subscribe(tokens)).await?;
+
+        Ok(())
+    }
+
+    // Hands a stream forwarded by the relay over to the peer that asked for
+    // it. Each tunnel starts with the public key of the peer on the other end.
+    async fn accept_tunnel(&mut self, mut reader: RecvStream, writer: SendStream) {
+        let mut key = [0; 32];
+        if let Err(e) = reader.read_exact(&mut key).await {
+            event!(Level::DEBUG, "Couldn't read tunnel header: {e}");
+            return;
+        }
+
+        let Ok(key) = VerifyingKey::from_bytes(&key)
+        else {
+            event!(Level::DEBUG, "Received a tunnel with an invalid key");
+            return;
+        };
+
+        match self.connections.get(&key) {
+            Some(handle) => {
+                event!(Level::INFO, "Accepting a relayed connection");
+                let _ = handle.tunnel_tx.send(tokio::io::join(reader, writer)).await;
+            }
+            None => {
+                event!(Level::DEBUG, "Ignoring a tunnel from an unknown peer");
+            }
+        }
+    }
 
-        let server_addr = self.relay.addr;
+    // Downloads a file from its sender and any other connected peer that
+    // has offered it to us. Nobody else is asked, so only peers that already
+    // know about the file learn that we're downloading it.
+    fn download(
+        &self,
+        from: VerifyingKey,
+        file: FileMetadata,
+        compression: Compression,
+        sources: &[VerifyingKey],
+    ) {
+        let sources = std::iter::once(&from)
+            .chain(sources.iter().filter(|key| **key != from))
+            .filter_map(|key| self.connections.get(key))
+            .filter(|handle| handle.is_running())
+            .map(|handle| handle.tx.clone())
+            .collect();
 
-        endpoint.set_default_client_config(
-            libchatty::quinn_session::configure_client(),
+        SwarmDownload::spawn(
+            file,
+            compression,
+            sources,
+            self.tx.clone(),
+            self.bandwidth.clone(),
+            &self.tracker,
+            self.token.clone(),
         );
+    }
+
+    // (Re)registers with the relays
+    async fn connect(&mut self) -> Result<Endpoint, Box<dyn Error + Send + Sync>> {
+        for session in self.sessions.drain(..) {
+            session.conn.close(0u32.into(), b"reconnecting");
+        }
+        self.responses = SelectAll::new();
+        self.presence.clear();
+        self.relay_retries.clear();
+
+        let endpoint = self.endpoint()?;
+        self.connect_relays(&endpoint).await?;
+
+        Ok(endpoint)
+    }
 
-        event!(Level::DEBUG, "Starting connection");
+    // Checks whether we've moved to another network, or have just woken up
+    // and may have been forgotten by the relays
+    fn network_changed(&mut self) -> bool {
+        let now = (Instant::now(), SystemTime::now());
+        let (instant, time) = std::mem::replace(&mut self.last_check, now);
+
+        let elapsed = now.1.duration_since(time).unwrap_or_default();
+        let asleep = elapsed.saturating_sub(now.0 - instant) > SUSPEND_THRESHOLD;
+
+        // The route to the relay reveals which interface we're using
+        let local_ip = self
+            .relays
+            .first()
+            .and_then(|relay| route_source(relay.addr).ok());
+        let moved = local_ip != self.local_ip;
+        self.local_ip = local_ip;
+
+        if asleep {
+            event!(Level::INFO, "Resumed after {elapsed:?}");
+        }
+        if moved {
+            event!(Level::INFO, "Local address changed to {local_ip:?}");
+        }
+
+        asleep || moved
+    }
+
+    // Registers with the relays again, so that they see our new address.
+    // Peer connections that we opened move to the new path on their own,
+    // the peer managers restart the rest.
+    async fn migrate(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _ = self.tx.send(ConnMessage::Connecting).await;
+
+        for handle in self.connections.values() {
+            let _ = handle.tx.try_send(PeerCommand::NetworkChanged);
+        }
+
+        // Contacts announce themselves again on the new network
+        self.lan_peers.clear();
+
+        self.connect().await?;
+        Ok(())
+    }
+
+    // Sets up the endpoint on first use, announces it on the local network
+    // and starts accepting peers on it
+    fn endpoint(&mut self) -> Result<Endpoint, Box<dyn Error + Send + Sync>> {
+        if let Some(endpoint) = &self.endpoint {
+            return Ok(endpoint.clone());
+        }
+
+        event!(Level::DEBUG, "Configuring self");
+        let endpoint = make_server_endpoint(&self.identity, self.listen_port)?;
+
+        LanDiscovery::spawn(
+            self.identity.clone(),
+            self.db.clone(),
+            endpoint.local_addr()?.port(),
+            self.lan_tx.clone(),
+            &self.tracker,
+            self.token.clone(),
+        );
+        self.accept_peers(endpoint.clone());
+
+        self.endpoint = Some(endpoint.clone());
+        Ok(endpoint)
+    }
+
+    // Completes the handshakes of incoming peer connections. Which peer a
+    // connection belongs to is only known from its certificate.
+    fn accept_peers(&self, endpoint: Endpoint) {
+        let incoming_tx = self.incoming_tx.clone();
+        let tracker = self.tracker.clone();
+        let token = self.token.clone();
+
+        self.tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(incoming) = endpoint.accept() => {
+                        let Ok(connecting) = incoming.accept()
+                        else {
+                            continue;
+                        };
+
+                        let incoming_tx = incoming_tx.clone();
+                        tracker.spawn(async move {
+                            match connecting.await {
+                                Ok(conn) => {
+                                    let _ = incoming_tx.send(conn).await;
+                                }
+                                Err(e) => {
+                                    event!(Level::DEBUG, "Incoming handshake failed: {e}");
+                                }
+                            }
+                        });
+                    }
+                    _ = token.cancelled() => break,
+                    else => break,
+                }
+            }
+        });
+    }
+
+    // Hands a connection over to the peer manager of whoever opened it.
+    // Contacts that we weren't expecting, because they connected to an
+    // address they already knew, get a new one.
+    fn accept_peer(&mut self, endpoint: &Endpoint, conn: Connection) {
+        let Some(key) = peer_identity(&conn)
+        else {
+            conn.close(0u32.into(), b"missing identity");
+            return;
+        };
+
+        if !self.is_connecting(&key) {
+            if !self.db.lock().unwrap().remote.contains_key(&key) {
+                event!(Level::DEBUG, "Refusing a connection from a stranger");
+                conn.close(0u32.into(), b"unknown identity");
+                return;
+            }
+
+            let addr = conn.remote_address();
+            event!(Level::INFO, "Accepting a direct connection from {addr}");
+            let mut candidates = vec![Candidate::new(CandidateKind::PeerReflexive, addr)];
+            ice::sort_candidates(&mut candidates);
+            let relay = self.sessions.first().map(|session| session.relay.public_key);
+            self.register_connection(endpoint.clone(), key, candidates, P2pRole::Responder, relay, PunchStrategy::Direct);
+        }
+
+        // Connections that nobody waits for are simply dropped
+        let _ = self.connections[&key].incoming_tx.try_send(conn);
+    }
+
+    // Connects to an address that we already know, without asking a relay
+    fn connect_directly(
+        &mut self,
+        endpoint: &Endpoint,
+        to: VerifyingKey,
+        addr: SocketAddr,
+    ) {
+        event!(Level::INFO, "Connecting directly to {addr}");
+        let relay = self.sessions.first().map(|session| session.relay.public_key);
+        let candidates = vec![Candidate::new(CandidateKind::Host, addr)];
+        self.register_connection(endpoint.clone(), to, candidates, P2pRole::Initiator, relay, PunchStrategy::Direct);
+    }
+
+    // Registers with every configured relay that we don't have a session
+    // with yet. Relays that can't be reached are skipped and tried again
+    // later, less often the longer they keep failing.
+    async fn connect_relays(
+        &mut self,
+        endpoint: &Endpoint,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let was_connected = !self.sessions.is_empty();
+        let now = Instant::now();
+        let mut added = false;
+
+        for relay in self.relays.clone() {
+            let waiting = self
+                .relay_retries
+                .get(&relay.public_key)
+                .is_some_and(|(at, _)| *at > now);
+            if waiting || self.find_session(&relay).is_some() {
+                continue;
+            }
+
+            match self.add_session(endpoint, relay.clone()).await {
+                Ok(_) => {
+                    self.relay_retries.remove(&relay.public_key);
+                    added = true;
+                }
+                Err(e) => {
+                    event!(Level::INFO, "Couldn't connect to {}: {e}", relay.addr);
+                    self.back_off(&relay);
+                }
+            }
+        }
+
+        if self.sessions.is_empty() {
+            event!(Level::INFO, "Couldn't connect to any relay. Retrying later.");
+            let _ = self.tx.send(ConnMessage::ServerOffline).await;
+            return Ok(());
+        }
+
+        if !added {
+            return Ok(());
+        }
+
+        event!(Level::INFO, "Connected to {} relay(s)", self.sessions.len());
+        if !was_connected {
+            let _ = self.tx.send(ConnMessage::Connected).await;
+        }
+
+        self.detect_nat(endpoint).await
+    }
+
+    // Doubles the wait before the next attempt to reach a relay
+    fn back_off(&mut self, relay: &Relay) {
+        let delay = self
+            .relay_retries
+            .get(&relay.public_key)
+            .map_or(RELAY_RETRY_INTERVAL, |(_, delay)| {
+                (*delay * 2).min(MAX_RELAY_BACKOFF)
+            });
+
+        self.relay_retries
+            .insert(relay.public_key, (Instant::now() + delay, delay));
+    }
+
+    fn is_missing_relays(&self) -> bool {
+        self.relays
+            .iter()
+            .any(|relay| self.find_session(relay).is_none())
+    }
+
+    fn find_session(&self, relay: &Relay) -> Option<SessionId> {
+        self.sessions
+            .iter()
+            .find(|session| session.relay.public_key == relay.public_key)
+            .map(|session| session.id)
+    }
+
+    fn session_mut(&mut self, id: SessionId) -> Option<&mut RelaySession> {
+        self.sessions.iter_mut().find(|session| session.id == id)
+    }
+
+    // Replies that a session still owes to earlier requests come before
+    // the one to the current request
+    fn owes_late_replies(&self, id: SessionId) -> bool {
+        self.sessions
+            .iter()
+            .any(|session| session.id == id && session.late_replies > 0)
+    }
+
+    // Forgets a relay session that ended, keeping the others. Contacts
+    // that were only seen through it are no longer known to be online.
+    async fn drop_session(&mut self, id: SessionId) {
+        let Some(position) = self.sessions.iter().position(|session| session.id == id)
+        else {
+            return;
+        };
+
+        let session = self.sessions.remove(position);
+        session.conn.close(0u32.into(), b"session ended");
+        event!(Level::INFO, "Lost connection to the relay at {}", session.relay.addr);
+
+        if let Some(conn) = self.relay_conns.get(&session.relay.public_key) {
+            conn.send_replace(None);
+        }
+
+        let mut offline = Vec::new();
+        for (contact, sessions) in self.presence.iter_mut() {
+            if sessions.remove(&id) && sessions.is_empty() {
+                offline.push(*contact);
+            }
+        }
+
+        for contact in offline {
+            let _ = self.tx.send(ConnMessage::Presence(contact, false)).await;
+        }
+
+        self.back_off(&session.relay);
+        if self.sessions.is_empty() {
+            let _ = self.tx.send(ConnMessage::ServerOffline).await;
+        }
+    }
+
+    // Connects and registers with a relay, returning the session id
+    async fn add_session(
+        &mut self,
+        endpoint: &Endpoint,
+        relay: Relay,
+    ) -> Result<SessionId, Box<dyn Error + Send + Sync>> {
+        event!(Level::DEBUG, "Starting connection to {}", relay.addr);
+        let config =
+            configure_client(self.identity.get_private_key(), &relay.public_key);
         let conn = endpoint
-            .connect(server_addr, "localhost")?
+            .connect_with(config, relay.addr, "localhost")?
             .await?;
 
         event!(Level::DEBUG, "Opened connection");
         let (writer, reader) = conn.open_bi().await?;
         let stream = tokio::io::join(reader, writer);
-        event!(Level::DEBUG, "Converted to a stream");
-        let mut stream = self.upgrade_relay_connection(stream).await?;
+        let mut stream = self.upgrade_relay_connection(&relay, stream).await?;
         event!(Level::DEBUG, "Upgraded the connection");
 
-        stream
-            .send(RelayRequest::Register(self.identity.get_public_key()))
-            .await?;
-        let _ack = stream.next().await;
+        let my_key = self.identity.get_public_key();
+        let request = match relay.invite.as_deref().map(Invite::decode) {
+            Some(Some(invite)) => RelayRequest::RegisterInvited(my_key, invite),
+            Some(None) => return Err("Invalid relay invite".into()),
+            None => RelayRequest::Register(my_key),
+        };
+
+        stream.send(request).await?;
+        let response = stream.next().await.ok_or("Connection closed")??;
+        if let RelayResponse::Error { code, message } = response {
+            return Err(format!("Registration refused ({code}): {message}").into());
+        }
+
+        self.share_presence(&mut stream).await?;
+
+        let candidates = gather_candidates(endpoint, &relay);
+        stream.send(RelayRequest::SetCandidates(candidates)).await?;
+
+        let id = self.next_session;
+        self.next_session += 1;
+
+        let (sink, stream) = stream.split();
+        self.responses.push(
+            stream
+                .map(Some)
+                .chain(futures::stream::once(async { None }))
+                .map(move |response| (id, response))
+                .boxed(),
+        );
 
-        event!(Level::INFO, "Connected to the server");
-        let _ = self.tx.send(ConnMessage::Connected).await;
+        self.accept_tunnels(conn.clone());
+        self.relay_conns
+            .entry(relay.public_key)
+            .or_insert_with(|| watch::Sender::new(None))
+            .send_replace(Some(conn.clone()));
 
-        Ok((endpoint, conn, stream))
+        event!(Level::INFO, "Connected to the relay at {}", relay.addr);
+        self.sessions.push(RelaySession {
+            id,
+            relay,
+            conn,
+            sink,
+            late_replies: 0,
+        });
+
+        Ok(id)
+    }
+
+    // Collects streams that a relay opens for tunneled peer connections
+    fn accept_tunnels(&self, conn: Connection) {
+        let tunnel_tx = self.tunnel_tx.clone();
+        let token = self.token.clone();
+
+        self.tracker.spawn(async move {
+            loop {
+                tokio::select! {
+                    Ok((writer, reader)) = conn.accept_bi() => {
+                        let _ = tunnel_tx.send((reader, writer)).await;
+                    }
+                    _ = token.cancelled() => break,
+                    else => break,
+                }
+            }
+        });
+    }
+
+    // Both sides of a contact see each other's announcements and start
+    // connecting at the same time. The one with the lower key initiates,
+    // so no relay has to introduce them.
+    fn found_on_lan(
+        &mut self,
+        endpoint: &Endpoint,
+        peer: VerifyingKey,
+        addr: SocketAddr,
+    ) {
+        if self.lan_peers.insert(peer, addr) == Some(addr)
+            || self.is_connecting(&peer)
+        {
+            return;
+        }
+
+        event!(Level::INFO, "Found a contact on the local network at {addr}");
+        let role = if self.identity.get_public_key().as_bytes() < peer.as_bytes() {
+            P2pRole::Initiator
+        }
+        else {
+            P2pRole::Responder
+        };
+
+        // A relay is still useful as a fallback, if there is one. There's
+        // no NAT in between peers on the same network.
+        let relay = self.sessions.first().map(|session| session.relay.public_key);
+        let candidates = vec![Candidate::new(CandidateKind::Host, addr)];
+        self.register_connection(endpoint.clone(), peer, candidates, role, relay, PunchStrategy::Direct);
     }
 
     fn register_connection(
         &mut self,
         endpoint: Endpoint,
         pubkey: VerifyingKey,
-        addr: SocketAddr,
+        candidates: Vec<Candidate>,
         role: P2pRole,
+        relay: Option<VerifyingKey>,
+        strategy: PunchStrategy,
     ) {
+        let relay_conn = match relay {
+            Some(relay) => self
+                .relay_conns
+                .entry(relay)
+                .or_insert_with(|| watch::Sender::new(None))
+                .subscribe(),
+            None => watch::Sender::new(None).subscribe(),
+        };
+
+        let context = PeerContext {
+            identity: self.identity.clone(),
+            token: self.token.clone(),
+            tracker: self.tracker.clone(),
+            message_consumer: self.tx.clone(),
+            db: self.db.clone(),
+            lookup_tx: self.lookup_tx.clone(),
+            bandwidth: self.bandwidth.clone(),
+            piece_hashes: self.piece_hashes.clone(),
+        };
+
         let handle = PeerManagerHandle::new(
-            self.identity.clone(),
+            context,
             endpoint,
             pubkey,
-            addr,
-            self.token.clone(),
+            candidates,
             role,
-            self.tracker.clone(),
-            self.tx.clone(),
-            self.db.clone()
+            relay_conn,
+            strategy,
         );
-        self.connections.insert(pubkey, handle);
+
+        // The relay asks us to connect again when the peer retries, the
+        // old attempt is of no use anymore
+        if let Some(old) = self.connections.insert(pubkey, handle) {
+            old.cancel();
+        }
     }
 
     async fn upgrade_relay_connection<T: Unpin + AsyncRead + AsyncWrite>(
-        &self,
+        &mut self,
+        relay: &Relay,
         stream: T,
     ) -> Result<RelayConnection<T>, Box<dyn Error + Send + Sync>> {
         let my_keys = utils::ed25519_to_noise(&self.identity.private_key);
         let server_key =
-            utils::ed25519_verifying_to_x25519(&self.relay.public_key);
+            utils::ed25519_verifying_to_x25519(&relay.public_key);
 
         let stream =
             NoiseBuilder::new(my_keys, stream)
@@ -187,39 +1221,85 @@ impl ConnManager {
     }
 }
 
-struct ConnCommand {
-    to: VerifyingKey,
-    command: PeerCommand
+enum ConnCommand {
+    Peer {
+        to: VerifyingKey,
+        command: PeerCommand
+    },
+    Download {
+        from: VerifyingKey,
+        file: FileMetadata,
+        compression: Compression,
+        // Other peers that have offered the file
+        sources: Vec<VerifyingKey>,
+    },
+    Connect {
+        to: VerifyingKey,
+        addr: SocketAddr,
+    },
 }
 
 #[derive(Debug)]
 pub struct ConnManagerHandle {
     tx: mpsc::Sender<ConnCommand>,
     task_tracker: TaskTracker,
+    /// Limits of the file transfers, changes apply to the running ones too
+    pub bandwidth: Bandwidth,
 }
 
 impl ConnManagerHandle {
     pub fn new(
         message_tx: mpsc::Sender<ConnMessage>,
         identity: Myself,
-        relay: Relay,
+        relays: Vec<Relay>,
         tracker: &TaskTracker,
         token: CancellationToken,
-        db: Arc<Mutex<UserDb>>
+        db: Arc<Mutex<UserDb>>,
+        settings: &Settings,
     ) -> Self {
         let (command_tx, command_rx) = mpsc::channel(32);
+        let listen_port = settings.listen_port;
+        let bandwidth = Bandwidth::new(settings.bandwidth);
+        let shared_bandwidth = bandwidth.clone();
+
+        let (tunnel_tx, tunnel_rx) = mpsc::channel(8);
+        let (lan_tx, lan_rx) = mpsc::channel(8);
+        let (incoming_tx, incoming_rx) = mpsc::channel(8);
+        let (lookup_tx, lookup_rx) = mpsc::channel(8);
 
         let inner_tracker = tracker.clone();
         tracker.spawn(async move {
             let mut conn_manager = ConnManager {
                 identity,
-                relay,
+                relays,
                 tx: message_tx.clone(),
                 rx: command_rx,
                 token: token.clone(),
                 tracker: inner_tracker,
                 connections: HashMap::new(),
-                db
+                db,
+                sessions: Vec::new(),
+                responses: SelectAll::new(),
+                next_session: 0,
+                relay_retries: HashMap::new(),
+                tunnel_tx,
+                tunnel_rx,
+                relay_conns: HashMap::new(),
+                presence: HashMap::new(),
+                nat: NatType::Unknown,
+                lan_tx,
+                lan_rx,
+                lan_peers: HashMap::new(),
+                incoming_tx,
+                incoming_rx,
+                lookup_tx,
+                lookup_rx,
+                endpoint: None,
+                listen_port,
+                local_ip: None,
+                last_check: (Instant::now(), SystemTime::now()),
+                bandwidth,
+                piece_hashes: PieceHashCache::default(),
             };
 
             // Warning! ConnManager keeps its state after a crash!
@@ -242,10 +1322,28 @@ impl ConnManagerHandle {
         Self {
             tx: command_tx,
             task_tracker: tracker.clone(),
+            bandwidth: shared_bandwidth,
         }
     }
 
     pub async fn send(&mut self, to: VerifyingKey, command: PeerCommand) {
-        let _ = self.tx.send(ConnCommand { to, command }).await;
+        let _ = self.tx.send(ConnCommand::Peer { to, command }).await;
+    }
+
+    pub async fn download(
+        &mut self,
+        from: VerifyingKey,
+        file: FileMetadata,
+        compression: Compression,
+        sources: Vec<VerifyingKey>,
+    ) {
+        let command =
+            ConnCommand::Download { from, file, compression, sources };
+        let _ = self.tx.send(command).await;
+    }
+
+    /// Connects to a peer at a known address, bypassing the relays
+    pub async fn connect(&mut self, to: VerifyingKey, addr: SocketAddr) {
+        let _ = self.tx.send(ConnCommand::Connect { to, addr }).await;
     }
 }
@@ -1,4 +1,5 @@
 use std::{
+    collections::{HashMap, HashSet},
     io::{self, Stdout},
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
@@ -14,6 +15,7 @@ use ratatui_image::picker::Picker;
 
 use crate::{
     action::AppAction,
+    bandwidth::Direction,
     connmanager::ConnManagerHandle,
     eventmanager::{AppEvent, EventManagerHandle},
     messagerepl::{Cli, Command, Parser},
@@ -25,10 +27,14 @@ use crate::{
 use libchatty::{
     identity::{Myself, Relay, UserDb},
     messaging::{PeerMessageData, UserMessage},
-    system::{FileHandle, FileMetadata},
+    settings::Settings,
+    store::FileStore,
+    system::{get_settings_path, FileHandle, FileMetadata, Hash},
 };
 
 use color_eyre::Result;
+use base64::prelude::*;
+use humansize::{format_size, DECIMAL};
 use tracing::{event, Level};
 
 type Term = Terminal<CrosstermBackend<Stdout>>;
@@ -41,18 +47,38 @@ pub struct AppController<'a> {
     tracker: TaskTracker,
     token: CancellationToken,
     db: Arc<Mutex<UserDb>>,
-    pending_download: Option<FileMetadata>,
+    pending_downloads: HashMap<Hash, FileMetadata>,
+    // The latest file offered by each contact
+    invites: HashMap<VerifyingKey, Hash>,
+    store: Option<FileStore>,
+    settings: Settings,
+}
+
+/// Everything the app is started with, besides the terminal
+pub struct AppContext {
+    pub tracker: TaskTracker,
+    pub token: CancellationToken,
+    pub db: Arc<Mutex<UserDb>>,
+    pub relays: Vec<Relay>,
+    pub store: Option<FileStore>,
+    pub settings: Settings,
 }
 
 impl<'a> AppController<'a> {
     pub fn new(
         msgs: Vec<String>,
         terminal: &'a mut Term,
-        tracker: TaskTracker,
-        token: CancellationToken,
-        db: Arc<Mutex<UserDb>>,
-        relay: Relay,
+        context: AppContext,
     ) -> Self {
+        let AppContext {
+            tracker,
+            token,
+            db,
+            relays,
+            store,
+            settings,
+        } = context;
+
         let mut picker = Picker::from_termios().unwrap();
         picker.guess_protocol();
         let tui = Tui::new(db.clone(), picker);
@@ -70,10 +96,11 @@ impl<'a> AppController<'a> {
         let conn_manager = ConnManagerHandle::new(
             message_tx,
             identity,
-            relay,
+            relays,
             &tracker,
             token.clone(),
             db.clone(),
+            &settings,
         );
 
         Self {
@@ -84,7 +111,10 @@ impl<'a> AppController<'a> {
             tracker,
             token,
             db,
-            pending_download: None,
+            pending_downloads: HashMap::new(),
+            invites: HashMap::new(),
+            store,
+            settings,
         }
     }
 
@@ -117,8 +147,26 @@ impl<'a> AppController<'a> {
             AppEvent::ReceiveMessage(msg) => {
                 Some(AppAction::ReceiveMessage(msg))
             }
-            AppEvent::NotifyDownloaded => {
-                Some(AppAction::ReceiveDownloadedFile)
+            AppEvent::NotifyDownloaded(hash) => {
+                Some(AppAction::ReceiveDownloadedFile(hash))
+            }
+            AppEvent::NotifyTransferFailed(hash, reason) => {
+                Some(AppAction::FailDownload(hash, reason))
+            }
+            AppEvent::NotifyPeerUnreachable(peer, reason) => {
+                Some(AppAction::FailPeerConnection(peer, reason))
+            }
+            AppEvent::SetPeerState(peer, state) => {
+                Some(AppAction::SetPeerState(peer, state))
+            }
+            AppEvent::SetPeerRtt(peer, rtt) => {
+                Some(AppAction::SetPeerRtt(peer, rtt))
+            }
+            AppEvent::SetPresence(peer, online) => {
+                Some(AppAction::SetPresence(peer, online))
+            }
+            AppEvent::SetNatReport(report) => {
+                Some(AppAction::SetNatReport(report))
             }
             AppEvent::SetConnected => Some(AppAction::SetConnected),
             AppEvent::SetConnecting => Some(AppAction::SetConnecting),
@@ -131,18 +179,34 @@ impl<'a> AppController<'a> {
         self.add_user_message(msg.author, msg.clone());
 
         match msg.content {
-            PeerMessageData::FileMeta(meta) => self.receive_invite(meta),
+            PeerMessageData::FileMeta(meta) => {
+                self.receive_invite(msg.author, meta)
+            }
             _ => None,
         }
     }
 
-    fn receive_invite(&mut self, invite: FileMetadata) -> Option<AppAction> {
-        self.pending_download = Some(invite);
+    fn receive_invite(
+        &mut self,
+        from: VerifyingKey,
+        invite: FileMetadata,
+    ) -> Option<AppAction> {
+        let hash = invite.hash;
+        let auto_download = {
+            let db = self.db.lock().unwrap();
+            self.settings.auto_download.allows(
+                &from,
+                db.remote.contains_key(&from) && db.verified.contains(&from),
+                &invite,
+                downloaded_size(&db),
+            )
+        };
+
+        self.invites.insert(from, hash);
+        self.pending_downloads.insert(hash, invite);
 
-        if let Some(t) = &self.pending_download.as_ref().unwrap().filetype {
-            if t.type_() == mime::IMAGE {
-                return Some(AppAction::DownloadFile);
-            }
+        if auto_download {
+            return Some(AppAction::DownloadFile(from, hash));
         }
 
         None
@@ -161,18 +225,118 @@ impl<'a> AppController<'a> {
 
         let action = match cli.command {
             Command::Share { path } => AppAction::ShareFile(path),
-            Command::Accept => AppAction::DownloadFile,
+            Command::Accept => {
+                let from = self.tui.get_current_user();
+                match self.invites.get(&from) {
+                    Some(hash) => AppAction::DownloadFile(from, *hash),
+                    None => {
+                        self.tui.add_notification(String::from(
+                            "There's no file to accept",
+                        ));
+                        return Ok(None);
+                    }
+                }
+            }
+            Command::Files => AppAction::ListFiles,
+            Command::Gc => AppAction::CollectGarbage,
+            Command::Limit { direction, rate, per_transfer } => {
+                AppAction::SetBandwidthLimit { direction, rate, per_transfer }
+            }
+            Command::Connect { contact, addr } => {
+                let Some(key) = self.find_contact(&contact)
+                else {
+                    return Ok(None);
+                };
+                match addr {
+                    Some(addr) => AppAction::ConnectDirectly(key, addr),
+                    None => AppAction::ForgetAddress(key),
+                }
+            }
+            Command::Verify { contact } => {
+                let Some(key) = self.find_contact(&contact)
+                else {
+                    return Ok(None);
+                };
+                AppAction::VerifyContact(key, true)
+            }
+            Command::Unverify { contact } => {
+                let Some(key) = self.find_contact(&contact)
+                else {
+                    return Ok(None);
+                };
+                AppAction::VerifyContact(key, false)
+            }
         };
 
         Ok(Some(action))
     }
 
+    fn find_contact(&mut self, nickname: &str) -> Option<VerifyingKey> {
+        let key = self.db.lock().unwrap().find_user_by_name(nickname).copied();
+        if key.is_none() {
+            self.tui.add_notification(format!(
+                "There's no contact called {nickname}"
+            ));
+        }
+
+        key
+    }
+
+    fn verify_contact(&mut self, peer: VerifyingKey, verified: bool) {
+        let mut db = self.db.lock().unwrap();
+        let name = db.remote.get(&peer).map_or(String::new(), |user| {
+            user.nickname.clone()
+        });
+
+        let note = if verified {
+            db.verified.insert(peer);
+            format!(
+                "Marked {name} as verified, their key is {}",
+                BASE64_STANDARD.encode(peer.as_bytes())
+            )
+        }
+        else {
+            db.verified.remove(&peer);
+            format!("{name} is no longer verified")
+        };
+
+        drop(db);
+        self.tui.add_notification(note);
+    }
+
+    // Applies a new limit to the running transfers right away, and keeps
+    // it for the next start
+    fn set_bandwidth_limit(
+        &mut self,
+        direction: Direction,
+        rate: u64,
+        per_transfer: bool,
+    ) {
+        let bandwidth = &self.conn_manager.bandwidth;
+        let mut limits = bandwidth.limits();
+        let limit = match (direction, per_transfer) {
+            (Direction::Upload, false) => &mut limits.upload,
+            (Direction::Download, false) => &mut limits.download,
+            (Direction::Upload, true) => &mut limits.transfer_upload,
+            (Direction::Download, true) => &mut limits.transfer_download,
+        };
+        *limit = rate;
+
+        bandwidth.set_limits(limits);
+        self.settings.bandwidth = limits;
+        self.settings.save(&get_settings_path());
+
+        let scope = if per_transfer { "per-transfer" } else { "total" };
+        let note = match rate {
+            0 => format!("Removed the {scope} {direction} limit"),
+            rate => format!("Limited the {scope} {direction} rate to {rate} KiB/s"),
+        };
+        self.tui.add_notification(note);
+    }
+
     async fn share_file(&mut self, path: PathBuf) -> Result<()> {
         let handle = FileHandle::new(path).await?;
-        {
-            let mut db = self.db.lock().unwrap();
-            db.add_file(handle.clone());
-        }
+        let handle = self.index_file(handle).await;
         let to = self.tui.get_current_user();
         let msg = PeerMessageData::FileMeta(handle.get_metadata().clone());
 
@@ -180,12 +344,121 @@ impl<'a> AppController<'a> {
         self.send_message(msg, to).await
     }
 
-    async fn get_file(&mut self) -> Result<()> {
-        let to = self.tui.get_current_user();
-        self.conn_manager.send(to, PeerCommand::GetFile).await;
+    // Copies the file into the content store (if enabled) and makes it
+    // available for upload
+    async fn index_file(&mut self, handle: FileHandle) -> FileHandle {
+        let handle = match &self.store {
+            Some(store) => match store.insert(&handle).await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    event!(Level::INFO, "Couldn't store file: {e}");
+                    handle
+                }
+            },
+            None => handle,
+        };
+
+        let mut db = self.db.lock().unwrap();
+        db.add_file(handle.clone());
+
+        handle
+    }
+
+    fn list_files(&mut self) {
+        let lines: Vec<String> = {
+            let db = self.db.lock().unwrap();
+            db.files
+                .values()
+                .map(|handle| {
+                    let meta = handle.get_metadata();
+                    let stored = self
+                        .store
+                        .as_ref()
+                        .is_some_and(|store| store.contains(&meta.hash));
+
+                    format!(
+                        "{} ({}) {}{}",
+                        meta.name,
+                        format_size(meta.size, DECIMAL),
+                        &meta.hash.to_hex()[..12],
+                        if stored { " [stored]" } else { "" }
+                    )
+                })
+                .collect()
+        };
+
+        if lines.is_empty() {
+            self.tui.add_notification(String::from("No files"));
+        }
+
+        for line in lines {
+            self.tui.add_notification(line);
+        }
+    }
+
+    async fn collect_garbage(&mut self) -> Result<()> {
+        let Some(store) = &self.store
+        else {
+            self.tui.add_notification(String::from("The file store is disabled"));
+            return Ok(());
+        };
+
+        let live: HashSet<Hash> = {
+            let mut db = self.db.lock().unwrap();
+
+            let mut referenced = offered_files(&db, true);
+            referenced.extend(offered_files(&db, false));
+
+            // Files shared from where they are, rather than from the store,
+            // stay available whether or not a conversation mentions them
+            db.files.retain(|hash, handle| {
+                let path = handle.get_path();
+                path.exists()
+                    && (referenced.contains(hash)
+                        || !path.starts_with(store.get_root()))
+            });
+
+            db.files.keys().copied().collect()
+        };
+
+        let freed = store.collect_garbage(&live).await?;
+        self.tui.add_notification(format!(
+            "Freed {}",
+            format_size(freed, DECIMAL)
+        ));
+
         Ok(())
     }
 
+    async fn get_file(&mut self, from: VerifyingKey, hash: Hash) -> Result<()> {
+        if let Some(meta) = self.pending_downloads.get(&hash) {
+            let compression = self.settings.compression.for_file(meta);
+            let sources = self.advertisers(hash);
+            self.conn_manager
+                .download(from, meta.clone(), compression, sources)
+                .await;
+        }
+        Ok(())
+    }
+
+    // The contacts that have offered us a file
+    fn advertisers(&self, hash: Hash) -> Vec<VerifyingKey> {
+        let db = self.db.lock().unwrap();
+        db.messages
+            .iter()
+            .filter(|(peer, log)| {
+                log.iter().any(|msg| {
+                    msg.author == **peer
+                        && matches!(
+                            &msg.content,
+                            PeerMessageData::FileMeta(meta) if meta.hash == hash
+                        )
+                })
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
     async fn send_message(
         &mut self,
         msg: PeerMessageData,
@@ -266,16 +539,89 @@ impl<'a> AppController<'a> {
                 self.share_file(path).await?;
                 None
             }
-            AppAction::DownloadFile => {
-                self.get_file().await?;
+            AppAction::ListFiles => {
+                self.list_files();
                 None
             }
-            AppAction::ReceiveDownloadedFile => {
-                let meta = self.pending_download.as_ref().unwrap();
-                self.parse_file(meta.clone(), meta.get_save_path()).await?;
+            AppAction::CollectGarbage => {
+                self.collect_garbage().await?;
+                None
+            }
+            AppAction::DownloadFile(from, hash) => {
+                self.get_file(from, hash).await?;
+                None
+            }
+            AppAction::ReceiveDownloadedFile(hash) => {
+                if let Some(meta) = self.pending_downloads.remove(&hash) {
+                    let handle = self.index_file(meta.get_local_handle()).await;
+                    self.parse_file(meta, handle.get_path().to_owned()).await?;
+                }
 
                 None
             }
+            AppAction::FailDownload(hash, reason) => {
+                let name = self
+                    .pending_downloads
+                    .remove(&hash)
+                    .map_or(hash.to_string(), |meta| meta.name);
+
+                self.tui.add_notification(format!(
+                    "Couldn't download {name}: {reason}"
+                ));
+
+                None
+            }
+            AppAction::FailPeerConnection(peer, reason) => {
+                let name = self.db.lock().unwrap().remote.get(&peer).map_or(
+                    BASE64_STANDARD.encode(peer.as_bytes()),
+                    |user| format!("{} {}", user.name, user.surname),
+                );
+
+                self.tui.add_notification(format!(
+                    "Couldn't reach {name}: {reason}"
+                ));
+
+                None
+            }
+            AppAction::ConnectDirectly(peer, addr) => {
+                // Later connections to the contact use the address too
+                self.db.lock().unwrap().addresses.insert(peer, addr);
+                self.conn_manager.connect(peer, addr).await;
+                self.tui.add_notification(format!("Connecting to {addr}"));
+                None
+            }
+            AppAction::ForgetAddress(peer) => {
+                let forgotten = self.db.lock().unwrap().addresses.remove(&peer);
+                self.tui.add_notification(match forgotten {
+                    Some(addr) => format!("Forgot {addr}"),
+                    None => String::from("There's no address to forget"),
+                });
+                None
+            }
+            AppAction::VerifyContact(peer, verified) => {
+                self.verify_contact(peer, verified);
+                None
+            }
+            AppAction::SetBandwidthLimit { direction, rate, per_transfer } => {
+                self.set_bandwidth_limit(direction, rate, per_transfer);
+                None
+            }
+            AppAction::SetPeerState(peer, state) => {
+                self.tui.set_peer_state(peer, state);
+                None
+            }
+            AppAction::SetPeerRtt(peer, rtt) => {
+                self.tui.set_peer_rtt(peer, rtt);
+                None
+            }
+            AppAction::SetPresence(peer, online) => {
+                self.tui.set_presence(peer, online);
+                None
+            }
+            AppAction::SetNatReport(report) => {
+                self.tui.set_nat_report(report);
+                None
+            }
             AppAction::SetConnected => {
                 self.tui.set_connected();
                 None
@@ -293,3 +639,30 @@ impl<'a> AppController<'a> {
         Ok(result)
     }
 }
+
+// Files offered in conversations, either by us or by our contacts
+fn offered_files(db: &UserDb, by_us: bool) -> HashSet<Hash> {
+    let me = db.myself.get_public_key();
+    db.messages
+        .values()
+        .flatten()
+        .filter(|msg| (msg.author == me) == by_us)
+        .filter_map(|msg| match &msg.content {
+            PeerMessageData::FileMeta(meta) => Some(meta.hash),
+            _ => None,
+        })
+        .collect()
+}
+
+// The total size of the files we downloaded, not counting the ones we
+// shared ourselves
+fn downloaded_size(db: &UserDb) -> u64 {
+    let shared = offered_files(db, true);
+    let received = offered_files(db, false);
+
+    db.files
+        .iter()
+        .filter(|(hash, _)| received.contains(hash) && !shared.contains(hash))
+        .map(|(_, handle)| handle.get_metadata().size)
+        .sum()
+}
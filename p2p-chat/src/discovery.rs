@@ -0,0 +1,185 @@
+use libchatty::{
+    identity::{Myself, UserDb},
+    lan::{self, LanAnnouncement, LAN_PORT},
+};
+
+use std::{
+    error::Error,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ed25519_dalek::VerifyingKey;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    time::{interval, sleep},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{event, Level};
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+// Pause after a failed receive, so that a broken socket can't spin
+const RECV_RETRY: Duration = Duration::from_millis(100);
+
+/// Finds contacts on the local network, without any relay. Every client
+/// broadcasts an announcement with a tag for each of its contacts, over
+/// IPv4 broadcast and IPv6 multicast, and reports the contacts whose
+/// announcements it receives.
+pub struct LanDiscovery {
+    identity: Myself,
+    db: Arc<Mutex<UserDb>>,
+    // Port of our QUIC endpoint
+    port: u16,
+    tx: mpsc::Sender<(VerifyingKey, SocketAddr)>,
+}
+
+impl LanDiscovery {
+    pub fn spawn(
+        identity: Myself,
+        db: Arc<Mutex<UserDb>>,
+        port: u16,
+        tx: mpsc::Sender<(VerifyingKey, SocketAddr)>,
+        tracker: &TaskTracker,
+        token: CancellationToken,
+    ) {
+        let discovery = Self {
+            identity,
+            db,
+            port,
+            tx,
+        };
+
+        tracker.spawn(async move {
+            tokio::select! {
+                result = discovery.run() => {
+                    if let Err(e) = result {
+                        event!(Level::WARN, "LAN discovery stopped: {e}");
+                    }
+                }
+                _ = token.cancelled() => {}
+            }
+        });
+    }
+
+    async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Either family may be missing on this host
+        let v4 = open_socket(lan::bind_discovery_socket(), "IPv4");
+        let v6 = open_socket(lan::bind_discovery_socket_v6(), "IPv6");
+        if v4.is_none() && v6.is_none() {
+            return Err("Couldn't open a discovery socket".into());
+        }
+
+        let groups = [
+            (&v4, SocketAddr::from((Ipv4Addr::BROADCAST, LAN_PORT))),
+            (&v6, SocketAddr::from((lan::LAN_GROUP_V6, LAN_PORT))),
+        ];
+
+        let mut ticker = interval(ANNOUNCE_INTERVAL);
+        let mut buf_v4 = vec![0; u16::MAX as usize];
+        let mut buf_v6 = vec![0; u16::MAX as usize];
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    let packet = self.announcement().encode()?;
+                    for (socket, group) in &groups {
+                        let Some(socket) = socket
+                        else {
+                            continue;
+                        };
+
+                        // There may be no network at all, which is no
+                        // reason to give up
+                        if let Err(e) = socket.send_to(&packet, group).await {
+                            event!(Level::DEBUG, "Couldn't announce ourselves to {group}: {e}");
+                        }
+                    }
+                }
+                received = recv_from(v4.as_ref(), &mut buf_v4) => {
+                    self.receive(received, &buf_v4).await
+                }
+                received = recv_from(v6.as_ref(), &mut buf_v6) => {
+                    self.receive(received, &buf_v6).await
+                }
+            }
+        }
+    }
+
+    // A failed receive, e.g. an ICMP error for an earlier announcement,
+    // doesn't stop discovery
+    async fn receive(
+        &self,
+        received: io::Result<(usize, SocketAddr)>,
+        buf: &[u8],
+    ) {
+        match received {
+            Ok((len, from)) => {
+                if let Some(announcement) = LanAnnouncement::decode(&buf[..len]) {
+                    self.handle_announcement(announcement, from).await;
+                }
+            }
+            Err(e) => {
+                event!(Level::DEBUG, "Couldn't receive an announcement: {e}");
+                sleep(RECV_RETRY).await;
+            }
+        }
+    }
+
+    fn announcement(&self) -> LanAnnouncement {
+        LanAnnouncement::new(
+            &self.identity.private_key,
+            &self.contacts(),
+            self.port,
+        )
+    }
+
+    async fn handle_announcement(
+        &self,
+        announcement: LanAnnouncement,
+        from: SocketAddr,
+    ) {
+        // Our own announcements never match, the tags are bound to the
+        // announcer's key
+        let contact = self.contacts().into_iter().find(|contact| {
+            announcement.is_from(&self.identity.private_key, contact)
+        });
+
+        // Link-local IPv6 addresses need the scope of the sender
+        if let Some(contact) = contact {
+            let mut addr = from;
+            addr.set_port(announcement.port);
+            let _ = self.tx.send((contact, addr)).await;
+        }
+    }
+
+    fn contacts(&self) -> Vec<VerifyingKey> {
+        self.db.lock().unwrap().remote.keys().copied().collect()
+    }
+}
+
+fn open_socket(
+    socket: io::Result<std::net::UdpSocket>,
+    family: &str,
+) -> Option<UdpSocket> {
+    match socket.and_then(UdpSocket::from_std) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            event!(Level::INFO, "No {family} LAN discovery: {e}");
+            None
+        }
+    }
+}
+
+// Never completes without a socket
+async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
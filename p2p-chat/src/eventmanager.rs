@@ -1,4 +1,4 @@
-use libchatty::messaging::UserMessage;
+use libchatty::{messaging::UserMessage, nat::NatReport, system::Hash};
 
 use tokio::{
     sync::mpsc,
@@ -10,7 +10,8 @@ use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use ratatui::crossterm::event::{self, KeyCode, KeyEvent, KeyModifiers};
 
-use crate::connmanager::ConnMessage;
+use crate::{connmanager::ConnMessage, peermanager::PeerState};
+use ed25519_dalek::VerifyingKey;
 
 #[derive(Debug)]
 pub struct PressedKey {
@@ -27,7 +28,13 @@ impl From<PressedKey> for KeyEvent {
 #[derive(Debug)]
 pub enum AppEvent {
     ReceiveMessage(UserMessage),
-    NotifyDownloaded,
+    NotifyDownloaded(Hash),
+    NotifyTransferFailed(Hash, String),
+    NotifyPeerUnreachable(VerifyingKey, String),
+    SetPeerState(VerifyingKey, PeerState),
+    SetPeerRtt(VerifyingKey, Duration),
+    SetPresence(VerifyingKey, bool),
+    SetNatReport(NatReport),
     SetOffline,
     SetConnecting,
     SetConnected,
@@ -55,7 +62,23 @@ impl EventManager {
                 Some(msg) = self.msg_rx.recv() => {
                     let event = match msg {
                         ConnMessage::UserMessage(msg) => AppEvent::ReceiveMessage(msg),
-                        ConnMessage::DownloadedFile => AppEvent::NotifyDownloaded,
+                        ConnMessage::DownloadedFile(hash) => AppEvent::NotifyDownloaded(hash),
+                        ConnMessage::TransferFailed { hash, reason } => {
+                            AppEvent::NotifyTransferFailed(hash, reason)
+                        }
+                        ConnMessage::PeerUnreachable { peer, reason } => {
+                            AppEvent::NotifyPeerUnreachable(peer, reason)
+                        }
+                        ConnMessage::PeerState(peer, state) => {
+                            AppEvent::SetPeerState(peer, state)
+                        }
+                        ConnMessage::PeerRtt(peer, rtt) => {
+                            AppEvent::SetPeerRtt(peer, rtt)
+                        }
+                        ConnMessage::Presence(peer, online) => {
+                            AppEvent::SetPresence(peer, online)
+                        }
+                        ConnMessage::Nat(report) => AppEvent::SetNatReport(report),
                         ConnMessage::ServerOffline => AppEvent::SetOffline,
                         ConnMessage::Connecting => AppEvent::SetConnecting,
                         ConnMessage::Connected => AppEvent::SetConnected
@@ -1,17 +1,19 @@
 use crate::{
     component::Component,
     action,
-    eventmanager::PressedKey
+    eventmanager::PressedKey,
+    peermanager::PeerState
 };
 
 use ed25519_dalek::VerifyingKey;
 use ratatui::{
     crossterm::event::KeyCode,
     prelude::*,
-    widgets::{Row, Table, TableState},
+    widgets::{Cell, Row, Table, TableState},
 };
 
 use base64::prelude::*;
+use std::time::Duration;
 
 use color_eyre::Result;
 
@@ -25,6 +27,10 @@ pub struct DisplayUser {
     pub name: String,
     pub surname: String,
     pub key: VerifyingKey,
+    pub state: Option<PeerState>,
+    // Round-trip time, while connected
+    pub rtt: Option<Duration>,
+    pub online: bool,
 }
 
 // TODO - optimize the string allocations away
@@ -36,6 +42,26 @@ impl DisplayUser {
     pub fn get_display_key(&self) -> String {
         BASE64_STANDARD.encode(self.key.as_bytes())
     }
+
+    pub fn get_display_state(&self) -> String {
+        self.state.map_or(String::new(), |state| state.to_string())
+    }
+
+    pub fn get_display_rtt(&self) -> String {
+        self.rtt
+            .map_or(String::new(), |rtt| format!("{} ms", rtt.as_millis()))
+    }
+
+    pub fn get_presence_cell(&self) -> Cell<'static> {
+        let color = if self.online {
+            Color::LightGreen
+        }
+        else {
+            Color::DarkGray
+        };
+
+        Cell::from("●").style(Style::new().fg(color))
+    }
 }
 
 impl FriendsView {
@@ -60,14 +86,53 @@ impl FriendsView {
     pub fn get_selected_user(&self) -> Option<VerifyingKey> {
         self.selected_user
     }
+
+    pub fn set_state(&mut self, key: VerifyingKey, state: PeerState) {
+        if let Some(user) = self.users.iter_mut().find(|user| user.key == key) {
+            if !matches!(state, PeerState::Connected { .. }) {
+                user.rtt = None;
+            }
+            user.state = Some(state);
+        }
+    }
+
+    pub fn set_rtt(&mut self, key: VerifyingKey, rtt: Duration) {
+        if let Some(user) = self.users.iter_mut().find(|user| user.key == key) {
+            user.rtt = Some(rtt);
+        }
+    }
+
+    pub fn set_presence(&mut self, key: VerifyingKey, online: bool) {
+        if let Some(user) = self.users.iter_mut().find(|user| user.key == key) {
+            user.online = online;
+        }
+    }
+
+    pub fn clear_presence(&mut self) {
+        for user in &mut self.users {
+            user.online = false;
+        }
+    }
 }
 
 impl Widget for &mut FriendsView {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let widths = [Constraint::Length(25), Constraint::Min(0)];
+        let widths = [
+            Constraint::Length(1),
+            Constraint::Length(25),
+            Constraint::Min(0),
+            Constraint::Length(14),
+            Constraint::Length(8),
+        ];
 
         let rows = self.users.iter().map(|user| {
-            Row::new(vec![user.get_full_display_name(), user.get_display_key()])
+            Row::new(vec![
+                user.get_presence_cell(),
+                user.get_full_display_name().into(),
+                user.get_display_key().into(),
+                user.get_display_state().into(),
+                user.get_display_rtt().into(),
+            ])
         });
 
         let table = Table::new(rows, widths)
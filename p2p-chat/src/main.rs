@@ -1,14 +1,18 @@
 mod component;
 mod action;
+mod bandwidth;
 mod connmanager;
 mod controller;
+mod discovery;
 mod eventmanager;
 mod friendsview;
 mod message;
 mod messagerepl;
 mod messageview;
+mod networkview;
 mod peermanager;
 mod spawner;
+mod swarm;
 mod tui;
 
 use crate::spawner::AppSpawner;
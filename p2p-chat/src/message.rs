@@ -15,6 +15,7 @@ use std::collections::HashMap;
 pub enum MessageSide {
     Sender,
     Responder,
+    System,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -54,6 +55,7 @@ impl DisplayMessageMetadata {
         match self.style.side {
             MessageSide::Sender => Color::Blue,
             MessageSide::Responder => Color::Green,
+            MessageSide::System => Color::Yellow,
         }
     }
 
@@ -1,7 +1,9 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 pub use clap::{Parser, Subcommand};
 
+use crate::bandwidth::Direction;
+
 #[derive(Debug, Parser)]
 #[command(multicall = true)]
 pub struct Cli {
@@ -12,5 +14,22 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 pub enum Command {
     Share { path: PathBuf },
-    Accept
+    Accept,
+    Files,
+    Gc,
+    /// Connects to a contact at a known address, without the relay. Leaving
+    /// out the address forgets the one given before.
+    Connect { contact: String, addr: Option<SocketAddr> },
+    /// Marks a contact whose key you've compared with them as verified
+    Verify { contact: String },
+    /// Takes back the verified mark of a contact
+    Unverify { contact: String },
+    /// Limits the file transfer rate in KiB/s, 0 removes the limit
+    Limit {
+        direction: Direction,
+        rate: u64,
+        /// Limits each file on its own instead of all of them together
+        #[arg(long)]
+        per_transfer: bool,
+    },
 }
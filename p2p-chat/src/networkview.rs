@@ -0,0 +1,77 @@
+use crate::{
+    component::Component,
+    action,
+    eventmanager::PressedKey,
+};
+
+use libchatty::nat::NatReport;
+
+use ratatui::{
+    prelude::*,
+    widgets::{Padding, Block, Paragraph},
+};
+
+use color_eyre::Result;
+
+/// Diagnostics about our connection to the outside world
+#[derive(Default)]
+pub struct NetworkView {
+    report: Option<NatReport>,
+}
+
+impl NetworkView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_report(&mut self, report: NatReport) {
+        self.report = Some(report);
+    }
+
+    pub fn clear_report(&mut self) {
+        self.report = None;
+    }
+}
+
+impl Widget for &mut NetworkView {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let lines = match &self.report {
+            Some(report) => {
+                let observed: Vec<String> = report
+                    .observed
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect();
+
+                vec![
+                    Line::from(format!("Local address:    {}", report.local)),
+                    Line::from(format!("Seen by relays:   {}", observed.join(", "))),
+                    Line::from(format!("NAT type:         {}", report.nat)),
+                    Line::from(format!("Peer connections: {}", report.nat.strategy())),
+                ]
+            }
+            None => vec![Line::from("Waiting for the relays...")],
+        };
+
+        Paragraph::new(lines)
+            .block(Block::default().padding(Padding::horizontal(1)))
+            .render(area, buf);
+    }
+}
+
+impl Component for NetworkView {
+    type Action = ();
+    type AppAction = action::AppAction;
+
+    fn draw(&mut self, frame: &mut Frame, area: Rect) {
+        frame.render_widget(self, area);
+    }
+
+    fn handle_kbd_event(&mut self, _key: PressedKey) -> Option<Self::Action> {
+        None
+    }
+
+    fn react(&mut self, _action: Self::Action) -> Result<Option<Self::AppAction>> {
+        Ok(None)
+    }
+}
@@ -1,14 +1,21 @@
 use libchatty::{
+    compression::Compression,
     identity::{Myself, UserDb},
+    ice::{Candidate, CandidateKind},
     messaging::{PeerMessageData, PeerPacket, UserMessage},
+    nat::PunchStrategy,
     noise_session::*,
     noise_transport::*,
-    system::{self, FileHandle, FileMetadata, Hash},
+    pieces,
+    quinn_session::configure_client,
+    system::{self, FileHandle, Hash},
     utils,
 };
 
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
+    io,
     net::SocketAddr,
     path::PathBuf,
     time::Duration,
@@ -16,58 +23,181 @@ use std::{
 };
 
 use ed25519_dalek::VerifyingKey;
-use futures::{sink::SinkExt, stream::StreamExt};
+use rand::Rng;
+use futures::{
+    future::{join_all, select_ok, FutureExt},
+    sink::SinkExt,
+    stream::{FuturesUnordered, StreamExt},
+};
 use quinn::{Connection, Endpoint};
 
 use tokio::{
-    fs::{File, OpenOptions},
-    io::AsyncReadExt,
-    sync::mpsc,
-    time::sleep,
+    sync::{mpsc, oneshot, watch, Mutex as AsyncMutex},
+    task::{self, JoinHandle},
+    time::{sleep, sleep_until, timeout, Instant},
 };
 
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{event, Level};
+use strum_macros::Display;
 
-use crate::connmanager::ConnMessage;
+use crate::{
+    bandwidth::{Bandwidth, Direction, TokenBucket},
+    connmanager::ConnMessage,
+};
 
-type QuinnStream = tokio::io::Join<quinn::RecvStream, quinn::SendStream>;
+pub type QuinnStream = tokio::io::Join<quinn::RecvStream, quinn::SendStream>;
 type PeerConnection = NoiseTransport<QuinnStream, PeerPacket, PeerPacket>;
+/// Piece hashes of the files that we've shared, by file hash
+pub type PieceHashCache = Arc<Mutex<HashMap<Hash, Vec<Hash>>>>;
+type Hashing = JoinHandle<(Hash, io::Result<Vec<Hash>>)>;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+// Ports past the one the relay saw that port prediction punches towards
+const PREDICTED_PORTS: u16 = 4;
+// Failed attempts in a row after which the peer is given up on
+const MAX_FAILURES: u32 = 8;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+const RESPONDER_RETRY: Duration = Duration::from_secs(3);
+// Time between the starts of two connectivity checks
+const CHECK_PACING: Duration = Duration::from_millis(50);
+// Head start that direct checks get over the relay
+const RELAY_HEAD_START: Duration = Duration::from_secs(2);
+// How often a connected peer is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+// A pong may take this many round-trip times, within the bounds below
+const PONG_TIMEOUT_RTTS: u32 = 4;
+const PONG_TIMEOUT_MIN: Duration = Duration::from_secs(1);
+const PONG_TIMEOUT_MAX: Duration = Duration::from_secs(10);
+// Pings in a row that may go unanswered before the peer is considered gone
+const MAX_MISSED_PINGS: u32 = 3;
+// Size of the chunks that pieces are sent in. Small enough for chat
+// messages to slip in between, and to stay within the bandwidth bucket at
+// low limits.
+const UPLOAD_CHUNK_LEN: usize = 16 * 1024;
+// Largest compressed piece that we accept
+const MAX_PIECE_DATA: usize = 2 * pieces::PIECE_LEN as usize;
+// Pieces that the peer may have waiting in our upload queue. A swarm asks
+// each peer for one piece at a time, so this only limits peers that ask
+// for many at once.
+const MAX_QUEUED_PIECES: usize = 8;
+
+// A file request that's waiting for the peer's response
+enum Request {
+    PieceHashes(oneshot::Sender<Option<Vec<Hash>>>),
+    // The chunks of the piece received so far
+    Piece(u64, Vec<u8>, oneshot::Sender<Option<Vec<u8>>>),
+}
+
+struct PendingRequest {
+    hash: Hash,
+    request: Request,
+    // When the request is given up on. Every chunk of a piece pushes it
+    // back, so slow transfers only fail when they stall.
+    deadline: Instant,
+}
 
 pub enum P2pRole {
     Initiator,
     Responder,
 }
 
+#[derive(Copy, Clone, Debug, Display)]
+pub enum PeerLink {
+    #[strum(to_string = "direct")]
+    Direct,
+    #[strum(to_string = "relayed")]
+    Relayed,
+}
+
+#[derive(Copy, Clone, Debug, Display)]
+pub enum PeerState {
+    /// Trying to reach the peer, directly or through the relay
+    #[strum(to_string = "punching")]
+    Punching,
+    #[strum(to_string = "{link}")]
+    Connected { link: PeerLink },
+    #[strum(to_string = "retry in {seconds}s")]
+    BackingOff { seconds: u64 },
+    #[strum(to_string = "unreachable")]
+    Failed,
+}
+
 struct PeerManager {
     identity: Myself,
     endpoint: Endpoint,
     peer_key: VerifyingKey,
-    peer_addr: SocketAddr,
+    // Where the peer can be reached, best first
+    candidates: Vec<Candidate>,
     token: CancellationToken,
     role: P2pRole,
     rx: mpsc::Receiver<PeerCommand>,
     tx: mpsc::Sender<ConnMessage>,
     conn: Option<PeerConnection>,
+    link: Option<PeerLink>,
+    relay: watch::Receiver<Option<Connection>>,
+    tunnel_rx: Option<mpsc::Receiver<QuinnStream>>,
+    // Direct connections from the peer, already checked by the connection
+    // manager
+    incoming_rx: AsyncMutex<mpsc::Receiver<Connection>>,
+    strategy: PunchStrategy,
+    // Failed attempts since the last successful connection
+    failures: u32,
+    // The ping that we're waiting for a pong to, and when it was sent
+    pending_ping: Option<(u64, Instant)>,
+    missed_pings: u32,
+    last_pong: Instant,
+    // When the peer last sent anything at all
+    last_received: Instant,
+    // Smoothed round-trip time of the current connection
+    rtt: Option<Duration>,
+    // Asks the connection manager where the peer can be reached now
+    lookup_tx: mpsc::Sender<(VerifyingKey, oneshot::Sender<Option<Vec<Candidate>>>)>,
+    bandwidth: Bandwidth,
+    // Chunks of pieces waiting for the bandwidth to send them. Chat
+    // messages skip the queue, so they wait for one chunk at most.
+    uploads: VecDeque<(Hash, PeerPacket)>,
+    // The per-transfer limit of each file that's being uploaded
+    upload_buckets: HashMap<Hash, TokenBucket>,
+    piece_hashes: PieceHashCache,
+    // Piece lists that are being computed for the peer
+    hashing: FuturesUnordered<Hashing>,
+    // Our own requests, which are answered in between everything else
+    requests: Vec<PendingRequest>,
     // TODO - replace this with a database of invites
     sent_invite: Option<FileHandle>,
-    recv_invite: Option<FileMetadata>,
     db: Arc<Mutex<UserDb>>
 }
 
 impl PeerManager {
     async fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        event!(Level::DEBUG, "Trying to hole-punch...");
         self.connect().await?;
 
         loop {
+            let ping_deadline = self.ping_deadline();
+            let upload_delay = self.upload_delay();
+            let request_deadline = self.request_deadline();
+
             tokio::select! {
                 Some(Ok(packet)) = self.conn.as_mut().unwrap().next() => {
+                    self.last_received = Instant::now();
                     self.handle_incoming_packet(packet).await?
                 }
                 Some(command) = self.rx.recv() => {
                     self.handle_egress_command(command).await?
                 }
+                Some(hashed) = self.hashing.next() => {
+                    self.answer_piece_hashes(hashed).await?
+                }
+                _ = sleep_until(ping_deadline) => self.ping().await?,
+                _ = sleep(upload_delay.unwrap_or_default()), if upload_delay.is_some() => {
+                    self.send_upload().await?
+                }
+                _ = sleep_until(request_deadline.unwrap_or_else(Instant::now)), if request_deadline.is_some() => {
+                    self.expire_requests()
+                }
                 _ = self.token.cancelled() => { break; }
                 else => { self.token.cancel() }
             }
@@ -82,25 +212,145 @@ impl PeerManager {
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         match command {
             PeerCommand::Send(msg) => self.send_message(msg).await?,
-            PeerCommand::GetFile => self.download_file().await?,
+            PeerCommand::GetPieceHashes(hash, reply) => {
+                let request = PeerPacket::GetPieceHashes(hash);
+                self.request(request, hash, Request::PieceHashes(reply))
+                    .await?
+            }
+            PeerCommand::GetPiece(hash, index, compression, reply) => {
+                let request = PeerPacket::GetPiece(hash, index, compression);
+                let request_kind = Request::Piece(index, Vec::new(), reply);
+                self.request(request, hash, request_kind).await?
+            }
+            PeerCommand::NetworkChanged => self.migrate()?,
         }
 
         Ok(())
     }
 
+    // Only the client side of a QUIC connection can move it to a new path.
+    // When we accepted the connection the peer can't follow us, and tunnels
+    // die with the relay connection, so those start over.
+    fn migrate(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match (&self.role, self.link) {
+            (P2pRole::Initiator, Some(PeerLink::Direct)) => {
+                event!(Level::DEBUG, "Migrating the peer connection");
+                Ok(())
+            }
+            _ => {
+                // Dropping the streams closes the connection, so the peer
+                // notices right away
+                self.conn = None;
+                Err("Our network changed".into())
+            }
+        }
+    }
+
     async fn handle_incoming_packet(
         &mut self,
         packet: PeerPacket,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         match packet {
             PeerPacket::Send(msg) => self.receive_message(msg).await?,
-            PeerPacket::GetFile(hash) => self.upload_file(hash).await?,
+            // Whole files used to be streamed outside of Noise, they're
+            // only served piece by piece now
+            PeerPacket::GetFile(hash) => {
+                self.send_packet(PeerPacket::NotFound(hash)).await?
+            }
+            PeerPacket::GetPieceHashes(hash) => {
+                self.send_piece_hashes(hash).await?
+            }
+            PeerPacket::GetPiece(hash, index, compression) => {
+                self.upload_piece(hash, index, compression).await?
+            }
+            PeerPacket::Ping(nonce) => {
+                self.send_packet(PeerPacket::Pong(nonce)).await?
+            }
+            PeerPacket::Pong(nonce) => self.handle_pong(nonce).await,
+            PeerPacket::PieceHashes(hash, hashes) => {
+                self.receive_piece_hashes(hash, hashes)
+            }
+            PeerPacket::PieceChunk(hash, index, data) => {
+                self.receive_piece_chunk(hash, index, data)
+            }
+            PeerPacket::Piece(hash, index, compression, data) => {
+                self.receive_piece(hash, index, compression, data)
+            }
+            PeerPacket::NotFound(hash) => self.receive_not_found(hash),
             _ => (),
         }
 
         Ok(())
     }
 
+    // When to send the next ping, or to give up on the current one. How
+    // long a pong may take follows the round-trip time, so a dead peer on a
+    // fast link is noticed within seconds.
+    fn ping_deadline(&self) -> Instant {
+        match self.pending_ping {
+            Some((_, sent)) => {
+                let timeout = self.rtt.map_or(PONG_TIMEOUT_MAX, |rtt| {
+                    (rtt * PONG_TIMEOUT_RTTS)
+                        .clamp(PONG_TIMEOUT_MIN, PONG_TIMEOUT_MAX)
+                });
+                sent + timeout
+            }
+            None => self.last_pong + PING_INTERVAL,
+        }
+    }
+
+    // Sends a ping, counting the previous one as missed if it's still
+    // unanswered. A peer that misses too many is reconnected to.
+    async fn ping(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some((_, sent)) = self.pending_ping.take() {
+            // The pong may be stuck behind file data, anything else that
+            // the peer sent in the meantime shows that it's still there
+            if self.last_received > sent {
+                self.missed_pings = 0;
+                self.last_pong = self.last_received;
+                return Ok(());
+            }
+
+            self.missed_pings += 1;
+            event!(Level::DEBUG, "Missed pong ({})", self.missed_pings);
+
+            if self.missed_pings >= MAX_MISSED_PINGS {
+                self.conn = None;
+                return Err("The peer stopped responding".into());
+            }
+        }
+
+        let nonce = rand::random();
+        self.pending_ping = Some((nonce, Instant::now()));
+        self.send_packet(PeerPacket::Ping(nonce)).await
+    }
+
+    // Pongs to pings that were already given up on are ignored
+    async fn handle_pong(&mut self, nonce: u64) {
+        let Some((_, sent)) = self
+            .pending_ping
+            .filter(|(pending, _)| *pending == nonce)
+        else {
+            return;
+        };
+
+        let sample = sent.elapsed();
+        let rtt = match self.rtt {
+            Some(rtt) => rtt.mul_f64(0.875) + sample.mul_f64(0.125),
+            None => sample,
+        };
+
+        self.rtt = Some(rtt);
+        self.pending_ping = None;
+        self.missed_pings = 0;
+        self.last_pong = Instant::now();
+
+        let _ = self
+            .tx
+            .send(ConnMessage::PeerRtt(self.peer_key, rtt))
+            .await;
+    }
+
     async fn send_packet(
         &mut self,
         msg: PeerPacket,
@@ -130,10 +380,6 @@ impl PeerManager {
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         event!(Level::INFO, "Received message: {:?}", msg);
 
-        if let PeerMessageData::FileMeta(meta) = &msg {
-            self.recv_invite = Some(meta.clone())
-        }
-
         self.tx
             .send(ConnMessage::UserMessage(UserMessage::new(
                 self.peer_key,
@@ -144,122 +390,563 @@ impl PeerManager {
         Ok(())
     }
 
-    // TODO: Add an error type
-    async fn upload_file(
+    fn get_file_handle(&self, hash: &Hash) -> Option<FileHandle> {
+        let db = self.db.lock().unwrap();
+        db.get_file(hash).cloned()
+    }
+
+    // Hashing a large file takes a while, so it's done on a blocking
+    // thread and answered once it's done. The result is kept for the next
+    // peer that asks.
+    async fn send_piece_hashes(
         &mut self,
-        hash: Hash
+        hash: Hash,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let handle = {
-            let db = self.db.lock().unwrap();
-            match db.get_file(&hash) {
-                None => {
-                    event!(Level::INFO, "Couldn't upload file - file not found");
-                    return Ok(());
-                }
-                Some(handle) => handle.clone()
-            }
+        let Some(handle) = self.get_file_handle(&hash)
+        else {
+            return self.send_packet(PeerPacket::NotFound(hash)).await;
         };
 
-        let mut file = handle.open().await?;
-        let mut socket = self.conn.as_mut().unwrap().get_mut();
+        let cached = self.piece_hashes.lock().unwrap().get(&hash).cloned();
+        if let Some(hashes) = cached {
+            let packet = PeerPacket::PieceHashes(hash, hashes);
+            return self.send_packet(packet).await;
+        }
 
-        event!(Level::INFO, "Beginning file upload");
-        tokio::io::copy(&mut file, &mut socket).await?;
-        event!(Level::INFO, "Finished uploading");
+        let path = handle.get_path().to_owned();
+        let size = handle.get_metadata().size;
+        self.hashing.push(task::spawn_blocking(move || {
+            let hashes = std::fs::File::open(path)
+                .and_then(|mut file| pieces::hash_file_pieces(&mut file, size));
+            (hash, hashes)
+        }));
 
         Ok(())
-        
     }
 
-    async fn download_file(
+    async fn answer_piece_hashes(
         &mut self,
+        hashed: Result<(Hash, io::Result<Vec<Hash>>), task::JoinError>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let invite = self
-            .recv_invite
-            .as_ref()
-            .ok_or("Can't download without a matching invite.")?
-            .clone();
-
-        let save_path = invite.get_save_path();
-
-        event!(Level::DEBUG, "Preparing for download, saving file @ {:?}", save_path);
-        
-        let mut file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&save_path)
-            .await?;
+        let (hash, hashes) = hashed?;
+
+        let Ok(hashes) = hashes
+        else {
+            event!(Level::INFO, "Couldn't hash the pieces of a shared file");
+            return self.send_packet(PeerPacket::NotFound(hash)).await;
+        };
+
+        self.piece_hashes
+            .lock()
+            .unwrap()
+            .insert(hash, hashes.clone());
+        self.send_packet(PeerPacket::PieceHashes(hash, hashes)).await
+    }
+
+    async fn upload_piece(
+        &mut self,
+        hash: Hash,
+        index: u64,
+        compression: Compression,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let handle = self
+            .get_file_handle(&hash)
+            .filter(|handle| {
+                index < pieces::piece_count(handle.get_metadata().size)
+            });
+
+        let Some(handle) = handle
+        else {
+            event!(Level::INFO, "Couldn't upload piece - file not found");
+            return self.send_packet(PeerPacket::NotFound(hash)).await;
+        };
+
+        // The peer asks someone else for the pieces we turn down
+        if self.queued_pieces() >= MAX_QUEUED_PIECES {
+            event!(Level::DEBUG, "Turning down piece {index}, too many queued");
+            return self.send_packet(PeerPacket::NotFound(hash)).await;
+        }
 
-        self.send_packet(PeerPacket::GetFile(invite.hash)).await?;
+        let mut file = handle.open().await?;
+        let size = handle.get_metadata().size;
+        let data = pieces::read_piece(&mut file, size, index).await?;
 
-        // TODO - handle the case when the peer doesn't have the requested file
-        let mut socket =
-            self.conn.as_mut().unwrap().get_mut().take(invite.size);
+        let compression = compression.for_file(handle.get_metadata());
+        let mut data = compression.compress(data)?;
+
+        // The last chunk goes out as the piece itself
+        let last = data.len().saturating_sub(1) / UPLOAD_CHUNK_LEN;
+        let tail = data.split_off(last * UPLOAD_CHUNK_LEN);
+
+        for chunk in data.chunks(UPLOAD_CHUNK_LEN) {
+            let chunk = PeerPacket::PieceChunk(hash, index, chunk.to_vec());
+            self.uploads.push_back((hash, chunk));
+        }
+
+        let piece = PeerPacket::Piece(hash, index, compression, tail);
+        self.uploads.push_back((hash, piece));
+
+        Ok(())
+    }
+
+    // Every queued piece ends with its Piece packet
+    fn queued_pieces(&self) -> usize {
+        self.uploads
+            .iter()
+            .filter(|(_, packet)| matches!(packet, PeerPacket::Piece(..)))
+            .count()
+    }
 
-        event!(Level::INFO, "Beginning file download");
-        tokio::io::copy(&mut socket, &mut file).await?;
+    // How long until the next queued chunk may be sent, None if there's
+    // nothing to send
+    fn upload_delay(&mut self) -> Option<Duration> {
+        let (hash, _) = self.uploads.front()?;
+        let transfer = self.upload_buckets.entry(*hash).or_default();
+        Some(self.bandwidth.delay(Direction::Upload, transfer))
+    }
 
-        if utils::get_hash_from_path(&save_path).await? != invite.hash {
-            // TODO - handle file hash not matching
+    // Sends the next queued chunk, unless other uploads have used up the
+    // bandwidth in the meantime
+    async fn send_upload(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.upload_delay() != Some(Duration::ZERO) {
+            return Ok(());
         }
-        
-        event!(Level::INFO, "Finished downloading");
 
-        self.tx.send(ConnMessage::DownloadedFile).await?;
+        let Some((hash, piece)) = self.uploads.pop_front()
+        else {
+            return Ok(());
+        };
+
+        let len = match &piece {
+            PeerPacket::Piece(_, _, _, data) => data.len(),
+            PeerPacket::PieceChunk(_, _, data) => data.len(),
+            _ => 0,
+        };
+
+        let transfer = self.upload_buckets.entry(hash).or_default();
+        self.bandwidth.take(Direction::Upload, transfer, len);
+
+        // Transfers that went quiet are forgotten
+        let queued: HashSet<Hash> =
+            self.uploads.iter().map(|(hash, _)| *hash).collect();
+        self.upload_buckets
+            .retain(|hash, bucket| queued.contains(hash) || !bucket.is_full());
+
+        self.send_packet(piece).await
+    }
+
+    async fn request(
+        &mut self,
+        packet: PeerPacket,
+        hash: Hash,
+        request: Request,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.send_packet(packet).await?;
+        self.requests.push(PendingRequest {
+            hash,
+            request,
+            deadline: Instant::now() + RESPONSE_TIMEOUT,
+        });
 
         Ok(())
     }
 
+    fn request_deadline(&self) -> Option<Instant> {
+        self.requests.iter().map(|pending| pending.deadline).min()
+    }
+
+    // Requests that the peer didn't answer in time fail, dropping the
+    // reply sender tells the requester
+    fn expire_requests(&mut self) {
+        let now = Instant::now();
+        self.requests.retain(|pending| pending.deadline > now);
+    }
+
+    // Finds the oldest pending request that `matches` accepts
+    fn find_request(
+        &self,
+        hash: Hash,
+        matches: impl Fn(&Request) -> bool,
+    ) -> Option<usize> {
+        self.requests.iter().position(|pending| {
+            pending.hash == hash && matches(&pending.request)
+        })
+    }
+
+    fn take_request(
+        &mut self,
+        hash: Hash,
+        matches: impl Fn(&Request) -> bool,
+    ) -> Option<Request> {
+        let i = self.find_request(hash, matches)?;
+        Some(self.requests.remove(i).request)
+    }
+
+    fn is_piece(request: &Request, index: u64) -> bool {
+        matches!(request, Request::Piece(i, _, _) if *i == index)
+    }
+
+    fn receive_piece_hashes(&mut self, hash: Hash, hashes: Vec<Hash>) {
+        let request = self.take_request(hash, |request| {
+            matches!(request, Request::PieceHashes(_))
+        });
+
+        if let Some(Request::PieceHashes(reply)) = request {
+            let _ = reply.send(Some(hashes));
+        }
+    }
+
+    fn receive_piece_chunk(&mut self, hash: Hash, index: u64, data: Vec<u8>) {
+        let Some(i) =
+            self.find_request(hash, |request| Self::is_piece(request, index))
+        else {
+            return;
+        };
+
+        let pending = &mut self.requests[i];
+        let Request::Piece(_, received, _) = &mut pending.request
+        else {
+            return;
+        };
+
+        if received.len() + data.len() > MAX_PIECE_DATA {
+            event!(Level::DEBUG, "Peer sent an oversized piece {index}");
+            self.requests.remove(i);
+            return;
+        }
+
+        received.extend_from_slice(&data);
+        pending.deadline = Instant::now() + RESPONSE_TIMEOUT;
+    }
+
+    // The raw data is verified later on, so a piece that can't be
+    // decompressed is simply treated as a missing one
+    fn receive_piece(
+        &mut self,
+        hash: Hash,
+        index: u64,
+        compression: Compression,
+        data: Vec<u8>,
+    ) {
+        let request =
+            self.take_request(hash, |request| Self::is_piece(request, index));
+
+        if let Some(Request::Piece(_, mut received, reply)) = request {
+            received.extend_from_slice(&data);
+            let data = compression
+                .decompress(received, pieces::PIECE_LEN as usize)
+                .ok();
+            let _ = reply.send(data);
+        }
+    }
+
+    // Every request for a file that the peer doesn't have fails
+    fn receive_not_found(&mut self, hash: Hash) {
+        self.requests.retain(|pending| pending.hash != hash);
+    }
+
     async fn connect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let (writer, reader) = {
-            let (incoming, outgoing) =
-                tokio::join!(self.accept_peer(), self.connect_to_peer());
+        self.report(PeerState::Punching).await;
 
-            event!(Level::DEBUG, "Hole punch success");
+        let (conn, link) = match self.role {
+            P2pRole::Initiator => self.run_checks().await?,
+            P2pRole::Responder => {
+                let mut tunnel_rx = self
+                    .tunnel_rx
+                    .take()
+                    .ok_or("Tunnel receiver is missing.")?;
 
-            match self.role {
-                P2pRole::Initiator => outgoing?.open_bi().await?,
-                P2pRole::Responder => incoming?.accept_bi().await?,
+                let result =
+                    timeout(PUNCH_TIMEOUT, self.answer_checks(&mut tunnel_rx)).await;
+                self.tunnel_rx = Some(tunnel_rx);
+                result??
             }
         };
 
-        let stream = tokio::io::join(reader, writer);
-        let stream = self.upgrade_connection(stream).await?;
-        self.conn = Some(stream);
+        self.conn = Some(conn);
+        self.link = Some(link);
+        self.failures = 0;
+
+        // The peer asks for the pieces that it's still missing again, and
+        // so do we
+        self.uploads.clear();
+        self.requests.clear();
+
+        // The new link may be faster or slower than the last one
+        self.pending_ping = None;
+        self.missed_pings = 0;
+        self.last_pong = Instant::now();
+        self.last_received = Instant::now();
+        self.rtt = None;
+
+        event!(Level::INFO, "Connected to peer ({link})");
+        self.report(PeerState::Connected { link }).await;
 
         Ok(())
     }
 
-    async fn accept_peer(
+    async fn report(&self, state: PeerState) {
+        let _ = self
+            .tx
+            .send(ConnMessage::PeerState(self.peer_key, state))
+            .await;
+    }
+
+    // How long to wait before the next attempt, None means giving up. The
+    // initiator backs off exponentially, with jitter so that peers that
+    // failed at the same time don't retry in lockstep. The responder can't
+    // do anything but wait for the initiator, so it stays ready.
+    fn next_retry(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        if self.failures > MAX_FAILURES {
+            return None;
+        }
+
+        match self.role {
+            P2pRole::Initiator => {
+                let delay = BACKOFF_BASE
+                    .saturating_mul(2_u32.pow(self.failures - 1))
+                    .min(BACKOFF_MAX);
+                Some(delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5)))
+            }
+            P2pRole::Responder => Some(RESPONDER_RETRY),
+        }
+    }
+
+    async fn give_up(&self, reason: String) {
+        self.report(PeerState::Failed).await;
+        let _ = self
+            .tx
+            .send(ConnMessage::PeerUnreachable {
+                peer: self.peer_key,
+                reason,
+            })
+            .await;
+    }
+
+    // The peer's address may have changed since the last attempt, so the
+    // initiator looks it up again. Looking the peer up through the relay
+    // also tells it to start punching again.
+    async fn refresh_address(&mut self) {
+        if !matches!(self.role, P2pRole::Initiator) {
+            return;
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.lookup_tx.send((self.peer_key, reply_tx)).await.is_err() {
+            return;
+        }
+
+        if let Ok(Some(candidates)) = reply_rx.await {
+            if !candidates.is_empty() && candidates != self.candidates {
+                event!(Level::INFO, "The peer moved to {candidates:?}");
+                self.candidates = candidates;
+            }
+        }
+    }
+
+    // Opens a stream that the relay forwards to the peer. The traffic is
+    // still end-to-end encrypted with Noise, the relay only sees ciphertext.
+    async fn open_tunnel(
+        &self,
+    ) -> Result<QuinnStream, Box<dyn Error + Send + Sync>> {
+        let relay = self
+            .relay
+            .borrow()
+            .clone()
+            .ok_or("Can't open a tunnel: not connected to the relay.")?;
+
+        let (mut writer, reader) = relay.open_bi().await?;
+        writer.write_all(self.peer_key.as_bytes()).await?;
+
+        Ok(tokio::io::join(reader, writer))
+    }
+
+    // The candidates to check, best first. With port prediction the next
+    // few ports of reflexive IPv4 candidates are tried as well, which covers
+    // NATs that allocate ports in sequence. Tunnels always go through our
+    // own relay, so a single relayed check is enough, and it's the last
+    // resort even when the peer didn't offer a relayed candidate.
+    fn check_list(&self) -> Vec<Candidate> {
+        let punch = self.strategy != PunchStrategy::Relay;
+        let predicted = match self.strategy {
+            PunchStrategy::PortPrediction => PREDICTED_PORTS,
+            _ => 0,
+        };
+
+        let mut checks: Vec<Candidate> = self
+            .candidates
+            .iter()
+            .filter(|candidate| punch && candidate.kind != CandidateKind::Relayed)
+            .flat_map(|candidate| {
+                let predicted = match candidate.kind {
+                    CandidateKind::Reflexive if candidate.addr.is_ipv4() => predicted,
+                    _ => 0,
+                };
+
+                let ip = candidate.addr.ip();
+                let port = candidate.addr.port();
+                (0..=predicted).map(move |offset| {
+                    let addr = SocketAddr::new(ip, port.wrapping_add(offset));
+                    Candidate::new(candidate.kind, addr)
+                })
+            })
+            .collect();
+
+        if let Some(relay) = self.relay.borrow().as_ref() {
+            let addr = relay.remote_address();
+            checks.push(Candidate::new(CandidateKind::Relayed, addr));
+        }
+
+        checks
+    }
+
+    // Starts a check for every candidate in order of priority, one every
+    // CHECK_PACING, and nominates the first one that completes the Noise
+    // handshake. Direct checks get a head start over the relay.
+    async fn run_checks(
+        &self,
+    ) -> Result<(PeerConnection, PeerLink), Box<dyn Error + Send + Sync>> {
+        let checks = self.check_list();
+        if checks.is_empty() {
+            return Err("No candidates to check".into());
+        }
+
+        event!(Level::DEBUG, "Checking {} candidate(s)...", checks.len());
+        let direct = checks
+            .iter()
+            .any(|candidate| candidate.kind != CandidateKind::Relayed);
+
+        let checks = checks.into_iter().enumerate().map(|(i, candidate)| {
+            let mut delay = CHECK_PACING * i as u32;
+            if direct && candidate.kind == CandidateKind::Relayed {
+                delay += RELAY_HEAD_START;
+            }
+
+            async move {
+                sleep(delay).await;
+                self.check(candidate).await
+            }
+            .boxed()
+        });
+
+        let ((mut conn, link), _) =
+            timeout(PUNCH_TIMEOUT, select_ok(checks)).await??;
+        conn.send(PeerPacket::Nominate).await?;
+
+        Ok((conn, link))
+    }
+
+    async fn check(
         &self,
-    ) -> Result<Connection, Box<dyn Error + Send + Sync>> {
+        candidate: Candidate,
+    ) -> Result<(PeerConnection, PeerLink), Box<dyn Error + Send + Sync>> {
+        let (stream, link) = match candidate.kind {
+            CandidateKind::Relayed => {
+                (self.open_tunnel().await?, PeerLink::Relayed)
+            }
+            _ => {
+                let config = configure_client(
+                    self.identity.get_private_key(),
+                    &self.peer_key,
+                );
+                let conn = self
+                    .endpoint
+                    .connect_with(config, candidate.addr, "localhost")?
+                    .await?;
+                let (writer, reader) = conn.open_bi().await?;
+                (tokio::io::join(reader, writer), PeerLink::Direct)
+            }
+        };
+
+        let conn = self.upgrade_connection(stream).await?;
+        event!(Level::DEBUG, "Check succeeded: {} {}", candidate.kind, candidate.addr);
+
+        Ok((conn, link))
+    }
+
+    // Answers the initiator's checks, on every connection it opens and
+    // every tunnel it sends, until it nominates one of them. Meanwhile we
+    // connect to its candidates too, which opens our NAT for its checks.
+    async fn answer_checks(
+        &self,
+        tunnel_rx: &mut mpsc::Receiver<QuinnStream>,
+    ) -> Result<(PeerConnection, PeerLink), Box<dyn Error + Send + Sync>> {
+        let mut incoming_rx = self.incoming_rx.lock().await;
+        let mut answers = FuturesUnordered::new();
+
+        let punch = self.punch();
+        tokio::pin!(punch);
+        let mut punching = true;
+
         loop {
-            let incoming = self
-                .endpoint
-                .accept()
-                .await
-                .ok_or("Peer closed the connetion prematurely.")?;
-
-            if incoming.remote_address() == self.peer_addr {
-                event!(Level::DEBUG, "Accepting remote...");
-                return Ok(incoming.accept()?.await?);
-            } else {
-                event!(Level::DEBUG, "Ignoring remote...");
-                incoming.ignore();
+            tokio::select! {
+                Some(conn) = incoming_rx.recv() => {
+                    // Connections from earlier attempts may be gone by now
+                    if conn.close_reason().is_none() {
+                        answers.push(self.answer_direct(conn).boxed());
+                    }
+                }
+                Some(stream) = tunnel_rx.recv() => {
+                    answers.push(self.answer(stream, PeerLink::Relayed).boxed());
+                }
+                Some(result) = answers.next() => match result {
+                    Ok(nominated) => return Ok(nominated),
+                    Err(e) => event!(Level::DEBUG, "Check failed: {e}"),
+                },
+                _ = &mut punch, if punching => punching = false,
+                else => return Err("Peer closed the connetion prematurely.".into()),
             }
         }
     }
 
-    async fn connect_to_peer(
+    async fn answer_direct(
         &self,
-    ) -> Result<Connection, Box<dyn Error + Send + Sync>> {
-        event!(Level::DEBUG, "Connecting to peer...");
-        let conn = self.endpoint.connect(self.peer_addr, "localhost")?.await?;
+        conn: Connection,
+    ) -> Result<(PeerConnection, PeerLink), Box<dyn Error + Send + Sync>> {
+        let (writer, reader) = conn.accept_bi().await?;
+        self.answer(tokio::io::join(reader, writer), PeerLink::Direct).await
+    }
 
-        event!(Level::DEBUG, "Connected to peer!");
+    // Completes the handshake of a check. The initiator nominates the pair
+    // that it picked by sending the first packet on it, the others are
+    // closed.
+    async fn answer(
+        &self,
+        stream: QuinnStream,
+        link: PeerLink,
+    ) -> Result<(PeerConnection, PeerLink), Box<dyn Error + Send + Sync>> {
+        let mut conn = self.upgrade_connection(stream).await?;
 
-        Ok(conn)
+        match conn.next().await {
+            Some(Ok(PeerPacket::Nominate)) => Ok((conn, link)),
+            _ => Err("The candidate wasn't nominated".into()),
+        }
+    }
+
+    // Connects to the initiator's direct candidates without using the
+    // connections, only their packets matter
+    async fn punch(&self) {
+        let attempts = self
+            .check_list()
+            .into_iter()
+            .filter(|candidate| candidate.kind != CandidateKind::Relayed)
+            .map(|candidate| {
+                let config = configure_client(
+                    self.identity.get_private_key(),
+                    &self.peer_key,
+                );
+
+                async move {
+                    if let Ok(connecting) =
+                        self.endpoint.connect_with(config, candidate.addr, "localhost")
+                    {
+                        let _ = connecting.await;
+                    }
+                }
+            });
+
+        join_all(attempts).await;
     }
 
     async fn upgrade_connection(
@@ -287,28 +974,61 @@ impl PeerManager {
 
 pub enum PeerCommand {
     Send(PeerMessageData),
-    GetFile,
+    GetPieceHashes(Hash, oneshot::Sender<Option<Vec<Hash>>>),
+    GetPiece(Hash, u64, Compression, oneshot::Sender<Option<Vec<u8>>>),
+    /// Our address has changed
+    NetworkChanged,
 }
 
 #[derive(Debug)]
 pub struct PeerManagerHandle {
     pub tx: mpsc::Sender<PeerCommand>,
+    pub tunnel_tx: mpsc::Sender<QuinnStream>,
+    pub incoming_tx: mpsc::Sender<Connection>,
+    token: CancellationToken,
     task_tracker: TaskTracker,
 }
 
+/// What every peer manager shares with the connection manager
+#[derive(Clone)]
+pub struct PeerContext {
+    pub identity: Myself,
+    pub token: CancellationToken,
+    pub tracker: TaskTracker,
+    pub message_consumer: mpsc::Sender<ConnMessage>,
+    pub db: Arc<Mutex<UserDb>>,
+    pub lookup_tx: mpsc::Sender<(VerifyingKey, oneshot::Sender<Option<Vec<Candidate>>>)>,
+    pub bandwidth: Bandwidth,
+    pub piece_hashes: PieceHashCache,
+}
+
 impl PeerManagerHandle {
     pub fn new(
-        identity: Myself,
+        context: PeerContext,
         endpoint: Endpoint,
         peer_key: VerifyingKey,
-        peer_addr: SocketAddr,
-        token: CancellationToken,
+        candidates: Vec<Candidate>,
         role: P2pRole,
-        tracker: TaskTracker,
-        message_consumer: mpsc::Sender<ConnMessage>,
-        db: Arc<Mutex<UserDb>>
+        relay: watch::Receiver<Option<Connection>>,
+        strategy: PunchStrategy,
     ) -> Self {
+        let PeerContext {
+            identity,
+            token,
+            tracker,
+            message_consumer,
+            db,
+            lookup_tx,
+            bandwidth,
+            piece_hashes,
+        } = context;
+
+        // Lets the peer be stopped on its own, e.g. when it's replaced
+        let token = token.child_token();
+        let task_token = token.clone();
         let (tx, rx) = mpsc::channel(32);
+        let (tunnel_tx, tunnel_rx) = mpsc::channel(1);
+        let (incoming_tx, incoming_rx) = mpsc::channel(4);
 
         // Spawns the peer manager actor hypervisor
         tracker.spawn(async move {
@@ -316,32 +1036,84 @@ impl PeerManagerHandle {
                 identity,
                 endpoint,
                 peer_key,
-                peer_addr,
-                token: token.clone(),
+                candidates,
+                token: task_token.clone(),
                 role,
                 rx,
                 tx: message_consumer,
                 conn: None,
+                link: None,
+                relay,
+                tunnel_rx: Some(tunnel_rx),
+                incoming_rx: AsyncMutex::new(incoming_rx),
+                strategy,
+                failures: 0,
+                pending_ping: None,
+                missed_pings: 0,
+                last_pong: Instant::now(),
+                last_received: Instant::now(),
+                rtt: None,
+                lookup_tx,
+                bandwidth,
+                uploads: VecDeque::new(),
+                upload_buckets: HashMap::new(),
+                piece_hashes,
+                hashing: FuturesUnordered::new(),
+                requests: Vec::new(),
                 sent_invite: None,
-                recv_invite: None,
                 db
             };
 
             loop {
-                match peer_manager.run().await {
-                    Ok(()) => break,
-                    Err(e) => {
-                        event!(Level::INFO, "Couldn't connect to the peer. Retrying in 3 seconds.");
-                        event!(Level::DEBUG, "Error: {}", e);
-                        sleep(Duration::from_secs(3)).await;
-                    }
+                let result = tokio::select! {
+                    result = peer_manager.run() => result,
+                    _ = task_token.cancelled() => break,
+                };
+
+                let Err(e) = result
+                else {
+                    break;
+                };
+                event!(Level::DEBUG, "Error: {}", e);
+
+                // Whoever is waiting for a response asks another peer
+                peer_manager.requests.clear();
+
+                let Some(delay) = peer_manager.next_retry()
+                else {
+                    event!(Level::INFO, "Couldn't connect to the peer. Giving up.");
+                    peer_manager.give_up(e.to_string()).await;
+                    break;
+                };
+
+                event!(Level::INFO, "Couldn't connect to the peer. Retrying in {delay:?}.");
+                let seconds = delay.as_secs();
+                peer_manager.report(PeerState::BackingOff { seconds }).await;
+
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = task_token.cancelled() => break,
                 }
+
+                peer_manager.refresh_address().await;
             }
         });
 
         Self {
             tx,
+            tunnel_tx,
+            incoming_tx,
+            token,
             task_tracker: tracker.clone(),
         }
     }
+
+    /// Whether the peer manager is still trying, it stops once it gives up
+    pub fn is_running(&self) -> bool {
+        !self.tx.is_closed()
+    }
+
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
 }
@@ -24,22 +24,41 @@ use color_eyre::Result;
 
 use clap::Parser;
 
-use crate::controller::AppController;
+use base64::prelude::*;
+use ed25519_dalek::VerifyingKey;
+
+use crate::controller::{AppContext, AppController};
 
 use libchatty::{
-    identity::{Myself, IdentityBuilder, Relay, User, UserDb},
+    identity::{Myself, IdentityBuilder, Relay, RelayList, User, UserDb},
+    settings::Settings,
+    store::FileStore,
     system::*
 };
 
 type Term = Terminal<CrosstermBackend<Stdout>>;
 
-use std::fs::{File, OpenOptions};
-use std::io::prelude::*;
+use std::fs::File;
 
 use tracing::Level;
 use tracing_appender::{non_blocking, non_blocking::WorkerGuard};
 use tracing_subscriber::filter::EnvFilter;
 
+fn default_relays() -> RelayList {
+    let public_key = "HwPfUAo36nOSDgX13tX1G+ELjoZOK91bL2mmpxu5iYA=";
+    let public_key = BASE64_STANDARD.decode(public_key).unwrap();
+
+    RelayList {
+        relays: vec![Relay {
+            addr: "153.19.219.152:55007".parse().unwrap(),
+            public_key: VerifyingKey::from_bytes(&public_key.try_into().unwrap())
+                .unwrap(),
+            priority: 0,
+            invite: None,
+        }],
+    }
+}
+
 fn init_tui() -> Result<Term> {
     stdout().execute(EnterAlternateScreen)?;
     enable_raw_mode()?;
@@ -155,41 +174,49 @@ impl AppSpawner {
             db.add_user(user);
         }
 
+        let relay_path = get_relay_path();
+
+        if !relay_path.exists() {
+            default_relays().save(&relay_path);
+        }
+
+        let relays = RelayList::load(&relay_path)?;
+
         if let Some(path) = args.export {
-            db.get_user_data().save_file(&path);
+            // Invites are personal and aren't shared with contacts
+            let relays = relays
+                .relays
+                .iter()
+                .map(|relay| Relay { invite: None, ..relay.clone() })
+                .collect();
+
+            db.get_user_data().with_relays(relays).save_file(&path);
             tracker.close();
             return Ok(Self { tracker });
         }
 
-        
-        let relay_path = get_relay_path();
-
-        if !relay_path.exists() {
-            let mut config = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .append(true)
-                .open(&relay_path)
-                .unwrap();
-
-            writeln!(config, r#"addr = "153.19.219.152:55007""#).unwrap();
-            writeln!(config, r#"public_key = "HwPfUAo36nOSDgX13tX1G+ELjoZOK91bL2mmpxu5iYA=""#).unwrap();
-        }
-        
-        let relay = Relay::load(&relay_path)?;
+        let settings = Settings::load_or_create(&get_settings_path())?;
 
         tracker.spawn(async move {
+            let store = if settings.content_store {
+                Some(FileStore::open(get_store_dir()).await?)
+            }
+            else {
+                None
+            };
+
             init_panic_hook();
             let messages = Vec::<String>::new();
             let mut terminal = init_tui()?;
-            let mut app = AppController::new(
-                messages,
-                &mut terminal,
-                app_tracker,
+            let context = AppContext {
+                tracker: app_tracker,
                 token,
-                Arc::new(Mutex::new(db)),
-                relay,
-            );
+                db: Arc::new(Mutex::new(db)),
+                relays: relays.relays,
+                store,
+                settings,
+            };
+            let mut app = AppController::new(messages, &mut terminal, context);
             let _tracing = _guard;
             app.run().await?;
             restore_tui()?;
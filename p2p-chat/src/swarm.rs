@@ -0,0 +1,258 @@
+use libchatty::{
+    compression::Compression,
+    pieces,
+    system::{FileMetadata, Hash},
+    utils,
+};
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::future::{join_all, try_join_all};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{event, Level};
+
+use crate::{
+    bandwidth::{Bandwidth, Direction, TokenBucket},
+    connmanager::ConnMessage,
+    peermanager::PeerCommand,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+type PeerTx = mpsc::Sender<PeerCommand>;
+
+/// Downloads a single file from every connected peer that offered it.
+/// Each peer serves different pieces, which are verified against the
+/// file hash before being written to disk. Pieces go into a temporary
+/// file that only takes the file's name once all of them check out.
+pub struct SwarmDownload {
+    file: FileMetadata,
+    compression: Compression,
+    sources: Vec<PeerTx>,
+    tx: mpsc::Sender<ConnMessage>,
+    bandwidth: Bandwidth,
+    // Shared by every peer that the file is downloaded from
+    transfer: Mutex<TokenBucket>,
+}
+
+impl SwarmDownload {
+    pub fn spawn(
+        file: FileMetadata,
+        compression: Compression,
+        sources: Vec<PeerTx>,
+        tx: mpsc::Sender<ConnMessage>,
+        bandwidth: Bandwidth,
+        tracker: &TaskTracker,
+        token: CancellationToken,
+    ) {
+        let swarm = Self {
+            file,
+            compression,
+            sources,
+            tx,
+            bandwidth,
+            transfer: Mutex::new(TokenBucket::new()),
+        };
+
+        tracker.spawn(async move {
+            tokio::select! {
+                _ = swarm.run() => {}
+                _ = token.cancelled() => {}
+            }
+        });
+    }
+
+    async fn run(&self) {
+        let hash = self.file.hash;
+        let temp = temp_path(&self.file.get_save_path());
+
+        // The temporary file is always a new one, so nothing but our own
+        // partial download is ever removed
+        let result = match File::create_new(&temp).await {
+            Ok(file) => {
+                let result = self.download(file, &temp).await;
+                if result.is_err() {
+                    let _ = fs::remove_file(&temp).await;
+                }
+                result
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        let msg = match result {
+            Ok(()) => {
+                event!(Level::INFO, "Finished downloading");
+                ConnMessage::DownloadedFile(hash)
+            }
+            Err(e) => {
+                event!(Level::INFO, "Download failed: {e}");
+                ConnMessage::TransferFailed {
+                    hash,
+                    reason: e.to_string(),
+                }
+            }
+        };
+
+        let _ = self.tx.send(msg).await;
+    }
+
+    async fn download(
+        &self,
+        file: File,
+        temp: &Path,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let size = self.file.size;
+        let hash = self.file.hash;
+
+        let replies = join_all(
+            self.sources.iter().map(|source| self.request_piece_hashes(source)),
+        )
+        .await;
+
+        // Only peers that know a valid piece list for this file can serve it
+        let mut piece_hashes = None;
+        let mut peers = Vec::new();
+        for (source, reply) in self.sources.iter().zip(replies) {
+            if let Some(hashes) = reply {
+                if pieces::verify_pieces(size, &hash, &hashes) {
+                    piece_hashes.get_or_insert(hashes);
+                    peers.push(source.clone());
+                }
+            }
+        }
+
+        let piece_hashes = piece_hashes.ok_or("No peer has this file")?;
+        let save_path = self.file.get_save_path();
+
+        event!(
+            Level::INFO,
+            "Downloading {:?} from {} peer(s)",
+            save_path,
+            peers.len()
+        );
+
+        file.set_len(size).await?;
+        drop(file);
+
+        let queue = Mutex::new((0..pieces::piece_count(size)).collect());
+
+        // Peers that fail to deliver a piece are dropped and their piece is
+        // handed to the remaining ones
+        while !is_empty(&queue) && !peers.is_empty() {
+            let healthy = try_join_all(peers.iter().map(|peer| {
+                self.download_pieces(peer, &queue, &piece_hashes, temp)
+            }))
+            .await?;
+
+            peers = peers
+                .into_iter()
+                .zip(healthy)
+                .filter_map(|(peer, healthy)| healthy.then_some(peer))
+                .collect();
+        }
+
+        if !is_empty(&queue) {
+            return Err("Couldn't download all pieces of the file".into());
+        }
+
+        if utils::get_hash_from_path(temp).await? != hash {
+            return Err("File hash mismatch".into());
+        }
+
+        fs::rename(temp, &save_path).await?;
+
+        Ok(())
+    }
+
+    // Downloads pieces from a single peer until there are none left.
+    // Returns false if the peer couldn't deliver a valid piece.
+    async fn download_pieces(
+        &self,
+        peer: &PeerTx,
+        queue: &Mutex<VecDeque<u64>>,
+        piece_hashes: &[Hash],
+        path: &Path,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let size = self.file.size;
+        let mut file = OpenOptions::new().write(true).open(path).await?;
+
+        loop {
+            let Some(index) = queue.lock().unwrap().pop_front()
+            else {
+                break;
+            };
+
+            // Pieces are only asked for as fast as the limits allow
+            let (_, len) = pieces::piece_range(size, index);
+            self.bandwidth
+                .acquire(Direction::Download, &self.transfer, len as usize)
+                .await;
+
+            let data = self
+                .request_piece(peer, index)
+                .await
+                .filter(|data| {
+                    pieces::hash_piece(size, index, data)
+                        == piece_hashes[index as usize]
+                });
+
+            let Some(data) = data
+            else {
+                event!(Level::DEBUG, "Peer failed to deliver piece {index}");
+                queue.lock().unwrap().push_back(index);
+                return Ok(false);
+            };
+
+            let (offset, _) = pieces::piece_range(size, index);
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(&data).await?;
+        }
+
+        file.flush().await?;
+
+        Ok(true)
+    }
+
+    async fn request_piece_hashes(&self, peer: &PeerTx) -> Option<Vec<Hash>> {
+        let (tx, rx) = oneshot::channel();
+        let command = PeerCommand::GetPieceHashes(self.file.hash, tx);
+        peer.send(command).await.ok()?;
+
+        timeout(REQUEST_TIMEOUT, rx).await.ok()?.ok()?
+    }
+
+    // A piece may take long at low limits, the peer manager gives up on it
+    // once it stops arriving
+    async fn request_piece(&self, peer: &PeerTx, index: u64) -> Option<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        let command =
+            PeerCommand::GetPiece(self.file.hash, index, self.compression, tx);
+        peer.send(command).await.ok()?;
+
+        rx.await.ok()?
+    }
+}
+
+// A hidden file next to the save path that no other download uses
+fn temp_path(save_path: &Path) -> PathBuf {
+    let name = save_path.file_name().unwrap_or_default().to_string_lossy();
+    let suffix: u64 = rand::random();
+    save_path.with_file_name(format!(".{name}.{suffix:016x}.part"))
+}
+
+fn is_empty(queue: &Mutex<VecDeque<u64>>) -> bool {
+    queue.lock().unwrap().is_empty()
+}
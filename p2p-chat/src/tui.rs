@@ -5,17 +5,21 @@ use crate::{
     friendsview::{DisplayUser, FriendsView, FriendsViewAction},
     message::{DisplayMessage, DisplayMessageMetadata, Content, MessageStyle, MessageSide, TextStyle},
     messageview::{MessageView, MessageViewAction},
+    networkview::NetworkView,
+    peermanager::PeerState,
 };
 
 use libchatty::{
     identity::UserDb,
     messaging::{PeerMessageData, UserMessage},
+    nat::NatReport,
     system::Hash
 };
 
 use std::{
     sync::{Arc, Mutex},
-    io::Stdout
+    io::Stdout,
+    time::Duration,
 };
 
 use ed25519_dalek::VerifyingKey;
@@ -42,6 +46,7 @@ pub struct Tui<'a> {
     message_view: MessageView<'a>,
     selected_tab: SelectedTab,
     friends_view: FriendsView,
+    network_view: NetworkView,
     db: Arc<Mutex<UserDb>>,
     conn_status: ConnectionStatus,
 }
@@ -52,6 +57,8 @@ enum SelectedTab {
     Friends,
     #[strum(to_string = "Messages")]
     Messages,
+    #[strum(to_string = "Network")]
+    Network,
 }
 
 #[derive(Copy, Clone, Display, EnumIter, FromRepr, EnumCountMacro)]
@@ -86,6 +93,9 @@ impl<'a> Tui<'a> {
                     name: v.name.clone(),
                     surname: v.surname.clone(),
                     key: k.clone(),
+                    state: None,
+                    rtt: None,
+                    online: false,
                 })
                 .collect()
         };
@@ -93,6 +103,7 @@ impl<'a> Tui<'a> {
         Self {
             message_view: MessageView::new(Vec::new(), picker),
             friends_view: FriendsView::new(friends),
+            network_view: NetworkView::new(),
             selected_tab: SelectedTab::Friends,
             db,
             conn_status: ConnectionStatus::Connecting,
@@ -121,6 +132,25 @@ impl<'a> Tui<'a> {
 
     pub fn set_offline(&mut self) {
         self.conn_status = ConnectionStatus::Offline;
+        // Presence is only known while connected to the relay
+        self.friends_view.clear_presence();
+        self.network_view.clear_report();
+    }
+
+    pub fn set_peer_state(&mut self, peer: VerifyingKey, state: PeerState) {
+        self.friends_view.set_state(peer, state);
+    }
+
+    pub fn set_peer_rtt(&mut self, peer: VerifyingKey, rtt: Duration) {
+        self.friends_view.set_rtt(peer, rtt);
+    }
+
+    pub fn set_presence(&mut self, peer: VerifyingKey, online: bool) {
+        self.friends_view.set_presence(peer, online);
+    }
+
+    pub fn set_nat_report(&mut self, report: NatReport) {
+        self.network_view.set_report(report);
     }
 
     pub fn draw(&mut self, terminal: &mut Term) -> Result<()> {
@@ -139,6 +169,9 @@ impl<'a> Tui<'a> {
                 SelectedTab::Messages => {
                     self.message_view.draw(frame, content)
                 }
+                SelectedTab::Network => {
+                    self.network_view.draw(frame, content)
+                }
             }
         })?;
 
@@ -208,6 +241,7 @@ impl<'a> Tui<'a> {
                         ))
                     })
                 }
+                SelectedTab::Network => None,
             }
         }
     }
@@ -309,6 +343,22 @@ impl<'a> Tui<'a> {
     pub fn add_image(&mut self, hash: Hash, image: DynamicImage) {
         self.message_view.add_image(hash, image);
     }
+
+    pub fn add_notification(&mut self, text: String) {
+        let message = DisplayMessage {
+            content: Content::Text(text),
+            meta: DisplayMessageMetadata {
+                author: String::from("aluminum"),
+                timestamp: Utc::now(),
+                style: MessageStyle {
+                    side: MessageSide::System,
+                    text: TextStyle::Info
+                }
+            }
+        };
+
+        self.message_view.append(message);
+    }
 }
 
 pub enum TuiAction {
@@ -0,0 +1,320 @@
+use base64::prelude::*;
+use ed25519_dalek::VerifyingKey;
+use libchatty::invite::Invite;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    hash::Hash,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::config::Config;
+
+// Buckets that haven't been touched for this long are full again and can
+// be forgotten
+const BUCKET_TTL: Duration = Duration::from_secs(60);
+const MAX_BUCKETS: usize = 10_000;
+
+/// A token bucket per key, refilled at a fixed rate per minute. At most
+/// MAX_BUCKETS keys are tracked, the least recently used one is dropped to
+/// make room for a new one.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    per_minute: u32,
+    buckets: HashMap<K, (f64, Instant)>,
+}
+
+impl<K: Eq + Hash + Copy> RateLimiter<K> {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Changes the rate, keeping what every key has used up
+    pub fn set_rate(&mut self, per_minute: u32) {
+        let capacity = per_minute as f64;
+        for (tokens, _) in self.buckets.values_mut() {
+            *tokens = tokens.min(capacity);
+        }
+        self.per_minute = per_minute;
+    }
+
+    /// Takes a token for the given key, returns false if none are left
+    pub fn check(&mut self, key: K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: K, now: Instant) -> bool {
+        let capacity = self.per_minute as f64;
+
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= MAX_BUCKETS {
+            self.buckets
+                .retain(|_, (_, updated)| now - *updated < BUCKET_TTL);
+
+            // Every key is still in use, the quietest one goes
+            if self.buckets.len() >= MAX_BUCKETS {
+                let oldest = self
+                    .buckets
+                    .iter()
+                    .min_by_key(|(_, (_, updated))| *updated)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    self.buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let (tokens, updated) =
+            self.buckets.entry(key).or_insert((capacity, now));
+
+        let refill = (now - *updated).as_secs_f64() * capacity / 60.0;
+        *tokens = (*tokens + refill).min(capacity);
+        *updated = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+/// Decides who may use the relay and how often
+#[derive(Debug)]
+pub struct AccessControl {
+    relay_key: VerifyingKey,
+    invite_only: bool,
+    allowlist: HashSet<VerifyingKey>,
+    banned: HashSet<VerifyingKey>,
+    connections_by_ip: RateLimiter<IpAddr>,
+    registrations_by_key: RateLimiter<VerifyingKey>,
+    lookups_by_ip: RateLimiter<IpAddr>,
+    lookups_by_key: RateLimiter<VerifyingKey>,
+}
+
+impl AccessControl {
+    pub fn new(config: &Config, relay_key: VerifyingKey) -> io::Result<Self> {
+        let allowlist = match &config.allowlist {
+            Some(path) => load_allowlist(path)?,
+            None => HashSet::new(),
+        };
+
+        let limits = &config.rate_limits;
+
+        Ok(Self {
+            relay_key,
+            invite_only: config.invite_only || config.allowlist.is_some(),
+            allowlist,
+            banned: HashSet::new(),
+            connections_by_ip: RateLimiter::new(limits.connections_per_minute),
+            registrations_by_key: RateLimiter::new(
+                limits.registrations_per_minute,
+            ),
+            lookups_by_ip: RateLimiter::new(limits.lookups_per_minute),
+            lookups_by_key: RateLimiter::new(limits.lookups_per_minute),
+        })
+    }
+
+    /// Applies a new configuration. Bans are kept, and so is what users
+    /// have used up of their rate limits.
+    pub fn reload(&mut self, config: &Config) -> io::Result<()> {
+        let allowlist = match &config.allowlist {
+            Some(path) => load_allowlist(path)?,
+            None => HashSet::new(),
+        };
+
+        let limits = &config.rate_limits;
+        self.invite_only = config.invite_only || config.allowlist.is_some();
+        self.allowlist = allowlist;
+        self.connections_by_ip.set_rate(limits.connections_per_minute);
+        self.registrations_by_key.set_rate(limits.registrations_per_minute);
+        self.lookups_by_ip.set_rate(limits.lookups_per_minute);
+        self.lookups_by_key.set_rate(limits.lookups_per_minute);
+
+        Ok(())
+    }
+
+    pub fn ban(&mut self, key: VerifyingKey) {
+        self.banned.insert(key);
+    }
+
+    pub fn unban(&mut self, key: &VerifyingKey) {
+        self.banned.remove(key);
+    }
+
+    pub fn is_banned(&self, key: &VerifyingKey) -> bool {
+        self.banned.contains(key)
+    }
+
+    pub fn banned(&self) -> &HashSet<VerifyingKey> {
+        &self.banned
+    }
+
+    /// Checks whether a user may register. On a private relay the user has
+    /// to be on the allowlist or present an invite.
+    pub fn admit(&self, key: &VerifyingKey, invite: Option<&Invite>) -> bool {
+        !self.invite_only
+            || self.allowlist.contains(key)
+            || invite.is_some_and(|invite| invite.verify(&self.relay_key, key))
+    }
+
+    pub fn allow_connection(&mut self, ip: IpAddr) -> bool {
+        self.connections_by_ip.check(subnet(ip))
+    }
+
+    pub fn allow_registration(&mut self, key: VerifyingKey) -> bool {
+        self.registrations_by_key.check(key)
+    }
+
+    pub fn allow_lookup(&mut self, key: VerifyingKey, ip: IpAddr) -> bool {
+        // Both buckets are always charged
+        let by_key = self.lookups_by_key.check(key);
+        let by_ip = self.lookups_by_ip.check(subnet(ip));
+        by_key && by_ip
+    }
+}
+
+// Hosts usually get a whole /64 of IPv6 addresses, so they're limited
+// together. Otherwise a single host could use a fresh address for every
+// attempt.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+        ip => ip,
+    }
+}
+
+/// Reads Base64 encoded public keys, one per line. Empty lines and lines
+/// starting with `#` are skipped.
+pub fn load_allowlist(path: &Path) -> io::Result<HashSet<VerifyingKey>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let key = BASE64_STANDARD
+                .decode(line)
+                .map_err(|e| invalid(format!("{line}: {e}")))?;
+            let key: [u8; 32] = key
+                .try_into()
+                .map_err(|_| invalid(format!("{line}: wrong key length")))?;
+            VerifyingKey::from_bytes(&key)
+                .map_err(|e| invalid(format!("{line}: {e}")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::RateLimits;
+
+    #[test]
+    fn limits_each_key() {
+        let mut limiter = RateLimiter::new(3);
+        for _ in 0..3 {
+            assert!(limiter.check(1));
+        }
+        assert!(!limiter.check(1));
+        assert!(limiter.check(2));
+    }
+
+    #[test]
+    fn refills_over_a_minute() {
+        let mut limiter = RateLimiter::new(6);
+        let start = Instant::now();
+        for _ in 0..6 {
+            assert!(limiter.check_at(1, start));
+        }
+        assert!(!limiter.check_at(1, start));
+
+        // One token every ten seconds
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check_at(1, later));
+        assert!(!limiter.check_at(1, later));
+
+        // Never more than the limit, however long the key was quiet
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..6 {
+            assert!(limiter.check_at(1, much_later));
+        }
+        assert!(!limiter.check_at(1, much_later));
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_keys() {
+        let mut limiter = RateLimiter::new(1);
+        let start = Instant::now();
+        for key in 0..MAX_BUCKETS as u32 + 100 {
+            let now = start + Duration::from_millis(key as u64);
+            assert!(limiter.check_at(key, now));
+        }
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS);
+
+        // The most recent keys are kept, the oldest ones made room
+        let now = start + Duration::from_millis(MAX_BUCKETS as u64 + 100);
+        assert!(!limiter.check_at(MAX_BUCKETS as u32 + 99, now));
+        assert!(!limiter.buckets.contains_key(&0));
+    }
+
+    #[test]
+    fn set_rate_keeps_used_tokens() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.check(1));
+        assert!(limiter.check(1));
+
+        limiter.set_rate(2);
+        assert!(!limiter.check(1));
+
+        limiter.set_rate(1);
+        assert!(!limiter.check(1));
+    }
+
+    #[test]
+    fn ipv6_hosts_are_limited_by_prefix() {
+        let ip = |ip: &str| subnet(ip.parse().unwrap());
+
+        assert_eq!(ip("2001:db8:1:2::1"), ip("2001:db8:1:2:ffff::7"));
+        assert_ne!(ip("2001:db8:1:2::1"), ip("2001:db8:1:3::1"));
+        assert_eq!(ip("::ffff:192.0.2.1"), ip("192.0.2.1"));
+        assert_ne!(ip("192.0.2.1"), ip("192.0.2.2"));
+    }
+
+    #[test]
+    fn reload_keeps_budgets() {
+        let config = Config {
+            rate_limits: RateLimits {
+                connections_per_minute: 2,
+                registrations_per_minute: 2,
+                lookups_per_minute: 2,
+            },
+            ..Config::default()
+        };
+        let relay_key =
+            ed25519_dalek::SigningKey::from_bytes(&[7; 32]).verifying_key();
+        let mut access = AccessControl::new(&config, relay_key).unwrap();
+
+        let ip = "2001:db8::1".parse().unwrap();
+        assert!(access.allow_connection(ip));
+        assert!(access.allow_connection(ip));
+        assert!(!access.allow_connection(ip));
+
+        access.ban(relay_key);
+        access.reload(&config).unwrap();
+        assert!(!access.allow_connection(ip));
+        assert!(access.is_banned(&relay_key));
+    }
+}
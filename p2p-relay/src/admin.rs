@@ -0,0 +1,351 @@
+use base64::prelude::*;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use libchatty::quinn_session::configure_server_with;
+use quinn::Endpoint;
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs,
+    net::SocketAddr,
+    io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener, UnixStream},
+};
+use tracing::{event, Level};
+
+use crate::{
+    access::AccessControl, config::Config, ConnectionDb, Notify, NotifyDb,
+};
+
+/// Counters exposed through the admin socket and the metrics endpoint
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    pub connections: AtomicUsize,
+    pub connections_total: AtomicU64,
+    pub lookups: AtomicU64,
+    pub notifications: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicUsize::new(0),
+            connections_total: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+            notifications: AtomicU64::new(0),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Average number of events per minute since the relay started
+    fn per_minute(&self, counter: &AtomicU64) -> f64 {
+        let minutes = self.uptime().as_secs_f64() / 60.0;
+        counter.load(Ordering::Relaxed) as f64 / minutes.max(1.0 / 60.0)
+    }
+}
+
+/// Changes the default log level of a running relay
+pub type SetLogLevel =
+    Box<dyn Fn(&str) -> Result<(), Box<dyn Error>> + Send + Sync>;
+
+/// Everything the admin interface can inspect or change
+pub struct Admin {
+    pub stats: Arc<Stats>,
+    pub conn_db: Arc<Mutex<ConnectionDb>>,
+    pub notify_db: Arc<Mutex<NotifyDb>>,
+    pub access: Arc<Mutex<AccessControl>>,
+    pub max_connections: Arc<AtomicUsize>,
+    /// The configuration in effect, replaced by `reload`
+    pub config: Mutex<Config>,
+    /// Reads the configuration again for `reload`
+    pub load_config:
+        Box<dyn Fn() -> Result<Config, Box<dyn Error>> + Send + Sync>,
+    pub set_log_level: SetLogLevel,
+    /// Rebuilding the server configuration needs the relay's key
+    pub identity: SigningKey,
+    pub endpoints: Vec<Endpoint>,
+}
+
+/// Creates the admin socket, refusing to take over the socket of a relay
+/// that's still running. The socket is set up in a private directory and
+/// only then moved into place, so nobody else can connect to it in between.
+pub async fn bind(path: &Path) -> io::Result<UnixListener> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another relay is using the admin socket {path:?}"),
+        ));
+    }
+
+    let name = path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let result = (|| {
+        let private = dir.join(name);
+        let listener = UnixListener::bind(&private)?;
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o600))?;
+
+        // Replaces a socket left behind by a previous run
+        fs::rename(&private, path)?;
+        Ok(listener)
+    })();
+
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+impl Admin {
+    /// Serves admin commands on a Unix socket, one command per connection
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin.handle(stream).await {
+                    event!(Level::DEBUG, "Admin connection failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        event!(Level::INFO, "Admin command: {line:?}");
+
+        let reply = match self.execute(&args) {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {e}\n"),
+        };
+
+        writer.write_all(reply.as_bytes()).await?;
+        writer.shutdown().await
+    }
+
+    fn execute(&self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        match args {
+            ["stats"] => Ok(self.get_stats()),
+            ["users"] => Ok(self.get_users()),
+            ["kick", key] => {
+                let kicked = self.kick(&parse_key(key)?, "Kicked by the operator");
+                Ok(format!("kicked {kicked} connection(s)\n"))
+            }
+            ["ban", key] => {
+                let key = parse_key(key)?;
+                self.access.lock().unwrap().ban(key);
+                let kicked = self.kick(&key, "Banned by the operator");
+                Ok(format!("banned, kicked {kicked} connection(s)\n"))
+            }
+            ["unban", key] => {
+                self.access.lock().unwrap().unban(&parse_key(key)?);
+                Ok(String::from("unbanned\n"))
+            }
+            ["reload"] => self.reload(),
+            _ => Ok(String::from(
+                "commands: stats, users, kick <key>, ban <key>, unban <key>, reload\n",
+            )),
+        }
+    }
+
+    // Applies everything that can change while running. Nothing is applied
+    // unless the whole configuration is valid.
+    fn reload(&self) -> Result<String, Box<dyn Error>> {
+        let config = (self.load_config)()?;
+        let server_config = configure_server_with(
+            &self.identity,
+            config.keepalive(),
+            Some(config.idle_timeout()),
+        )
+        .map_err(|e| e.to_string())?;
+        self.access.lock().unwrap().reload(&config)?;
+        (self.set_log_level)(&config.log_level)?;
+
+        // Connections that are already open keep their old timeouts
+        for endpoint in &self.endpoints {
+            endpoint.set_server_config(Some(server_config.clone()));
+        }
+        self.max_connections
+            .store(config.max_connections, Ordering::SeqCst);
+
+        let mut current = self.config.lock().unwrap();
+        let pending = current.restart_required(&config);
+        *current = config;
+
+        let mut reply = String::from("reloaded the configuration\n");
+        if !pending.is_empty() {
+            let _ = writeln!(reply, "restart to apply: {}", pending.join(", "));
+        }
+
+        Ok(reply)
+    }
+
+    fn get_stats(&self) -> String {
+        let stats = &self.stats;
+        let uptime = stats.uptime().as_secs();
+
+        format!(
+            "uptime: {}h {}m {}s\n\
+             connections: {} ({} total)\n\
+             registered users: {}\n\
+             lookups: {} ({:.1}/min)\n\
+             notifications: {} ({:.1}/min)\n",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            stats.connections.load(Ordering::Relaxed),
+            stats.connections_total.load(Ordering::Relaxed),
+            self.conn_db.lock().unwrap().len(),
+            stats.lookups.load(Ordering::Relaxed),
+            stats.per_minute(&stats.lookups),
+            stats.notifications.load(Ordering::Relaxed),
+            stats.per_minute(&stats.notifications),
+        )
+    }
+
+    fn get_users(&self) -> String {
+        let conn_db = self.conn_db.lock().unwrap();
+        let mut users = String::new();
+
+        for (key, registration) in conn_db.iter() {
+            let key = BASE64_STANDARD.encode(key.as_bytes());
+            let _ = writeln!(users, "{key} {}", registration.addr);
+        }
+
+        users
+    }
+
+    // Disconnects every connection registered under the key
+    fn kick(&self, key: &VerifyingKey, reason: &str) -> usize {
+        let addrs: Vec<SocketAddr> = self
+            .conn_db
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, registration)| registration.addr)
+            .collect();
+
+        let notify_db = self.notify_db.lock().unwrap();
+        addrs
+            .iter()
+            .filter_map(|addr| notify_db.get(addr))
+            .filter(|tx| tx.try_send(Notify::Kick(reason.to_string())).is_ok())
+            .count()
+    }
+
+    /// Serves the statistics in the Prometheus text format
+    pub async fn serve_metrics(
+        self: Arc<Self>,
+        addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        event!(Level::INFO, "Serving metrics on {addr}");
+
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let metrics = self.get_metrics();
+
+            tokio::spawn(async move {
+                // The request itself doesn't matter, every path serves metrics
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\
+                     Connection: close\r\n\r\n{metrics}",
+                    metrics.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    }
+
+    fn get_metrics(&self) -> String {
+        let stats = &self.stats;
+        let metrics = [
+            ("uptime_seconds", "gauge", stats.uptime().as_secs()),
+            (
+                "connections",
+                "gauge",
+                stats.connections.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "connections_total",
+                "counter",
+                stats.connections_total.load(Ordering::Relaxed),
+            ),
+            (
+                "registered_users",
+                "gauge",
+                self.conn_db.lock().unwrap().len() as u64,
+            ),
+            (
+                "lookups_total",
+                "counter",
+                stats.lookups.load(Ordering::Relaxed),
+            ),
+            (
+                "notifications_total",
+                "counter",
+                stats.notifications.load(Ordering::Relaxed),
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, value) in metrics {
+            let _ = writeln!(output, "# TYPE aluminum_relay_{name} {kind}");
+            let _ = writeln!(output, "aluminum_relay_{name} {value}");
+        }
+
+        output
+    }
+}
+
+/// Sends a command to a running relay and returns its reply
+pub async fn send_command(
+    path: &Path,
+    command: &[String],
+) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(format!("{}\n", command.join(" ")).as_bytes())
+        .await?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+
+    Ok(reply)
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey, Box<dyn Error>> {
+    let key = BASE64_STANDARD.decode(key)?;
+    let key: [u8; 32] =
+        key.try_into().map_err(|_| "Invalid public key length")?;
+    Ok(VerifyingKey::from_bytes(&key)?)
+}
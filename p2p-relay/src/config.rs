@@ -0,0 +1,132 @@
+use libchatty::identity::Relay;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Addresses to listen on, both IPv4 and IPv6 are supported
+    pub listen: Vec<SocketAddr>,
+    /// Second port to listen on, so that clients can tell how their NAT
+    /// assigns ports
+    pub probe_port: Option<u16>,
+    pub database: PathBuf,
+    /// Bans and presence subscriptions, kept across restarts
+    pub state: PathBuf,
+    /// Log file path, "-" logs to stderr
+    pub log_file: PathBuf,
+    pub log_level: String,
+    pub keepalive_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub max_connections: usize,
+    /// Relays to share the user directory with. Both sides have to list
+    /// each other.
+    pub federation: Vec<Relay>,
+    /// Only users that present an invite may register
+    pub invite_only: bool,
+    /// File with the public keys that may register without an invite.
+    /// Setting it makes the relay invite only.
+    pub allowlist: Option<PathBuf>,
+    pub rate_limits: RateLimits,
+    /// Unix socket for `p2p-relay admin`
+    pub admin_socket: PathBuf,
+    /// Serves Prometheus metrics on this address when set
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Connection attempts per IP address
+    pub connections_per_minute: u32,
+    /// Registrations per key
+    pub registrations_per_minute: u32,
+    /// User lookups per IP address and per key
+    pub lookups_per_minute: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            connections_per_minute: 30,
+            registrations_per_minute: 30,
+            lookups_per_minute: 60,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                "0.0.0.0:55007".parse().unwrap(),
+                "[::]:55007".parse().unwrap(),
+            ],
+            probe_port: None,
+            database: get_data_dir().join("server.db"),
+            state: get_data_dir().join("relay-state.db"),
+            log_file: PathBuf::from("server.log"),
+            log_level: String::from("debug"),
+            keepalive_secs: 20,
+            idle_timeout_secs: 60,
+            max_connections: 1024,
+            federation: Vec::new(),
+            invite_only: false,
+            allowlist: None,
+            rate_limits: RateLimits::default(),
+            admin_socket: get_data_dir().join("admin.sock"),
+            metrics_listen: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let config = fs::read_to_string(path)?;
+        toml::from_str::<Config>(&config)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn keepalive(&self) -> Duration {
+        Duration::from_secs(self.keepalive_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// The settings that differ from `other` but can't be changed without
+    /// restarting the relay
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let settings = [
+            ("listen", self.listen != other.listen),
+            ("probe_port", self.probe_port != other.probe_port),
+            ("database", self.database != other.database),
+            ("state", self.state != other.state),
+            ("log_file", self.log_file != other.log_file),
+            ("federation", self.federation != other.federation),
+            ("admin_socket", self.admin_socket != other.admin_socket),
+            ("metrics_listen", self.metrics_listen != other.metrics_listen),
+        ];
+
+        settings
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect()
+    }
+}
+
+pub fn get_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap()
+        .join("aluminum")
+}
+
+pub fn get_default_config_path() -> PathBuf {
+    get_data_dir().join("server.toml")
+}
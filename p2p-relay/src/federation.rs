@@ -0,0 +1,198 @@
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures::{future::join_all, sink::SinkExt, stream::StreamExt};
+use libchatty::{
+    ice::Candidate,
+    identity::Relay,
+    messaging::{ErrorCode, RelayRequest, RelayResponse},
+    noise_session::*,
+    noise_transport::*,
+    quinn_session::configure_client,
+    utils,
+};
+use quinn::{Endpoint, RecvStream, SendStream};
+use std::{error::Error, time::Duration};
+use tokio::{
+    io::Join,
+    sync::{mpsc, oneshot},
+    time::{sleep, timeout},
+};
+use tracing::{event, Level};
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+type FederationLink =
+    NoiseTransport<Join<RecvStream, SendStream>, RelayRequest, RelayResponse>;
+
+struct Lookup {
+    callee: VerifyingKey,
+    caller: VerifyingKey,
+    caller_candidates: Vec<Candidate>,
+    reply: oneshot::Sender<Option<Vec<Candidate>>>,
+}
+
+/// Links to other relays that users of this relay can be found through
+#[derive(Clone, Default)]
+pub struct Federation {
+    members: Vec<VerifyingKey>,
+    links: Vec<mpsc::Sender<Lookup>>,
+}
+
+impl Federation {
+    /// Keeps a link open to every federated relay
+    pub fn spawn(
+        peers: &[Relay],
+        endpoints: &[Endpoint],
+        identity: &SigningKey,
+    ) -> Self {
+        let mut federation = Self::default();
+
+        for peer in peers {
+            // The endpoint has to be able to reach the peer's address family
+            let Some(endpoint) = endpoints.iter().find(|endpoint| {
+                endpoint
+                    .local_addr()
+                    .is_ok_and(|addr| addr.is_ipv6() == peer.addr.is_ipv6())
+            })
+            else {
+                event!(Level::WARN, "No endpoint can reach the relay at {}", peer.addr);
+                continue;
+            };
+
+            let (tx, rx) = mpsc::channel(32);
+            tokio::spawn(maintain_link(
+                peer.clone(),
+                endpoint.clone(),
+                identity.clone(),
+                rx,
+            ));
+
+            federation.members.push(peer.public_key);
+            federation.links.push(tx);
+        }
+
+        federation
+    }
+
+    pub fn is_member(&self, key: &VerifyingKey) -> bool {
+        self.members.contains(key)
+    }
+
+    /// Asks every federated relay for a user. The relay that knows the
+    /// callee tells it to expect a connection from the caller.
+    pub async fn lookup(
+        &self,
+        callee: VerifyingKey,
+        caller: VerifyingKey,
+        caller_candidates: Vec<Candidate>,
+    ) -> Option<Vec<Candidate>> {
+        let replies = join_all(self.links.iter().map(|link| {
+            let caller_candidates = caller_candidates.clone();
+            async move {
+                let (reply, rx) = oneshot::channel();
+                let lookup = Lookup {
+                    callee,
+                    caller,
+                    caller_candidates,
+                    reply,
+                };
+
+                link.send(lookup).await.ok()?;
+                timeout(LOOKUP_TIMEOUT, rx).await.ok()?.ok()?
+            }
+        }))
+        .await;
+
+        replies.into_iter().flatten().next()
+    }
+}
+
+async fn maintain_link(
+    peer: Relay,
+    endpoint: Endpoint,
+    identity: SigningKey,
+    mut rx: mpsc::Receiver<Lookup>,
+) {
+    loop {
+        if let Err(e) = run_link(&peer, &endpoint, &identity, &mut rx).await {
+            event!(Level::INFO, "Federation link to {} failed: {e}", peer.addr);
+        }
+
+        if rx.is_closed() {
+            break;
+        }
+
+        sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn run_link(
+    peer: &Relay,
+    endpoint: &Endpoint,
+    identity: &SigningKey,
+    rx: &mut mpsc::Receiver<Lookup>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn = endpoint
+        .connect_with(
+            configure_client(identity, &peer.public_key),
+            peer.addr,
+            "localhost",
+        )?
+        .await?;
+
+    let (writer, reader) = conn.open_bi().await?;
+    let stream = tokio::io::join(reader, writer);
+
+    let my_keys = utils::ed25519_to_noise(identity);
+    let peer_key = utils::ed25519_verifying_to_x25519(&peer.public_key);
+
+    let socket = NoiseBuilder::new(my_keys, stream)
+        .set_my_type(NoiseSelfType::I)
+        .set_peer_type(NoisePeerType::K(peer_key))
+        .build_as_initiator()
+        .await?;
+
+    let mut link = FederationLink::new(socket);
+
+    link.send(RelayRequest::Federate(identity.verifying_key())).await?;
+    match link.next().await.ok_or("Link closed during setup")?? {
+        RelayResponse::Ack => {}
+        RelayResponse::Error { code, message } => {
+            return Err(format!("Federation was refused ({code}): {message}").into())
+        }
+        response => {
+            return Err(format!("Unexpected response: {response:?}").into())
+        }
+    }
+
+    event!(Level::INFO, "Federated with the relay at {}", peer.addr);
+
+    while let Some(lookup) = rx.recv().await {
+        // The user who asked may have given up already
+        if lookup.reply.is_closed() {
+            continue;
+        }
+
+        link.send(RelayRequest::Lookup {
+            callee: lookup.callee,
+            caller: lookup.caller,
+            caller_candidates: lookup.caller_candidates,
+        })
+        .await?;
+
+        let candidates = match link.next().await.ok_or("Link closed")?? {
+            RelayResponse::UserAddress(candidates) => Some(candidates),
+            RelayResponse::Error {
+                code: ErrorCode::UnknownUser,
+                ..
+            } => None,
+            response => {
+                return Err(format!("Unexpected response: {response:?}").into())
+            }
+        };
+
+        let _ = lookup.reply.send(candidates);
+    }
+
+    Ok(())
+}
@@ -1,9 +1,15 @@
 #![allow(unused)]
-use futures::{sink::SinkExt, stream::StreamExt};
+use futures::{
+    future::join_all,
+    sink::SinkExt,
+    stream::{SplitSink, SplitStream, StreamExt},
+};
 
 use libchatty::{
     identity::{Myself, UserDb, IdentityBuilder},
-    messaging::{RelayRequest, RelayResponse},
+    invite::Invite,
+    ice::{self, Candidate, CandidateKind},
+    messaging::{ErrorCode, RelayRequest, RelayResponse},
     noise_session::*,
     noise_transport::*,
     quinn_session::*,
@@ -14,10 +20,14 @@ use std::{
     error::Error,
     net::SocketAddr,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use base64::prelude::*;
 
@@ -38,37 +48,356 @@ use std::fs::File;
 
 use tokio::io::{AsyncRead, AsyncWrite, Join};
 
-use tokio::sync::mpsc;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
 
 use quinn::{Connection, RecvStream, SendStream};
 
-pub fn make_server_endpoint(
-    bind_addr: SocketAddr,
-) -> Result<
-    (Endpoint, CertificateDer<'static>),
-    Box<dyn Error + Send + Sync + 'static>,
-> {
-    let (server_config, server_cert) = configure_server()?;
-    let endpoint = Endpoint::server(server_config, bind_addr)?;
-    Ok((endpoint, server_cert))
+mod access;
+mod admin;
+mod config;
+mod federation;
+mod presence;
+mod state;
+
+use access::AccessControl;
+use admin::{Admin, SetLogLevel, Stats};
+use config::Config;
+use federation::Federation;
+use presence::PresenceDb;
+use state::RelayState;
+
+pub fn make_server_endpoints(
+    config: &Config,
+    identity: &SigningKey,
+) -> Result<Vec<Endpoint>, Box<dyn Error + Send + Sync + 'static>> {
+    let server_config = configure_server_with(
+        identity,
+        config.keepalive(),
+        Some(config.idle_timeout()),
+    )?;
+
+    let addrs = config.listen.iter().flat_map(|addr| {
+        let probe = config.probe_port.map(|port| SocketAddr::new(addr.ip(), port));
+        std::iter::once(*addr).chain(probe)
+    });
+
+    // Hosts without IPv6 can still serve IPv4 users with the default
+    // configuration
+    let mut endpoints = Vec::new();
+    for addr in addrs {
+        match make_endpoint(server_config.clone(), addr) {
+            Ok(endpoint) => endpoints.push(endpoint),
+            Err(e) => event!(Level::WARN, "Couldn't listen on {addr}: {e}"),
+        }
+    }
+
+    if endpoints.is_empty() {
+        return Err("Couldn't listen on any address".into());
+    }
+
+    Ok(endpoints)
 }
 
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type QuicStream = Join<RecvStream, SendStream>;
+type UserTransport = NoiseTransport<QuicStream, RelayResponse, RelayRequest>;
+
 enum Notify {
-    Call(SocketAddr),
+    // Another client with the given key and candidates wants to connect
+    Call(VerifyingKey, Vec<Candidate>),
+    // A stream from another client that should be forwarded to this one
+    Tunnel(VerifyingKey, QuicStream),
+    // The same user registered again from a different address
+    Replaced(SocketAddr),
+    // A subscribed contact went online or offline
+    Presence(VerifyingKey, bool),
+    // The operator disconnected this user
+    Kick(String),
+}
+
+/// Where a registered user can be reached. The address of its connection
+/// identifies the registration, the user adds the candidates it gathered
+/// itself, e.g. the addresses of its interfaces.
+#[derive(Clone, Debug)]
+pub struct Registration {
+    pub addr: SocketAddr,
+    pub candidates: Vec<Candidate>,
+}
+
+impl Registration {
+    fn candidates(&self) -> Vec<Candidate> {
+        let mut candidates = self.candidates.clone();
+        candidates.push(Candidate::new(CandidateKind::Reflexive, self.addr));
+        ice::sort_candidates(&mut candidates);
+        candidates
+    }
 }
 
-type ConnectionDb = HashMap<VerifyingKey, SocketAddr>;
+// Users advertising more candidates than this are cut short
+const MAX_CANDIDATES: usize = 8;
+
+type ConnectionDb = HashMap<VerifyingKey, Registration>;
 type NotifyDb = HashMap<SocketAddr, mpsc::Sender<Notify>>;
-type QuicStream = Join<RecvStream, SendStream>;
 
-async fn process(
-    conn: Incoming,
+// Registers a user under a new address. If the user was already registered
+// from somewhere else, the old connection is told to close.
+fn register(
+    key: VerifyingKey,
+    addr: SocketAddr,
+    notify_tx: &mpsc::Sender<Notify>,
+    conn_db: &Mutex<ConnectionDb>,
+    notify_db: &Mutex<NotifyDb>,
+) {
+    notify_db.lock().unwrap().insert(addr, notify_tx.clone());
+    let candidates = Vec::new();
+    let previous = conn_db
+        .lock()
+        .unwrap()
+        .insert(key, Registration { addr, candidates })
+        .map(|previous| previous.addr);
+
+    if let Some(previous) = previous.filter(|previous| *previous != addr) {
+        event!(Level::INFO, "User moved from {previous} to {addr}");
+        if let Some(tx) = notify_db.lock().unwrap().get(&previous) {
+            let _ = tx.try_send(Notify::Replaced(addr));
+        }
+    }
+}
+
+// Removes everything that refers to a connection that has gone away.
+// Registrations that have since moved to another address, or a newer
+// connection from the same address, are kept.
+fn unregister(
+    addr: SocketAddr,
+    notify_tx: &mpsc::Sender<Notify>,
+    conn_db: &Mutex<ConnectionDb>,
+    notify_db: &Mutex<NotifyDb>,
+    presence_db: &Mutex<PresenceDb>,
+) {
+    let gone: Vec<VerifyingKey> = {
+        let mut notify_db = notify_db.lock().unwrap();
+        if !notify_db.get(&addr).is_some_and(|tx| tx.same_channel(notify_tx)) {
+            return;
+        }
+        notify_db.remove(&addr);
+
+        let mut conn_db = conn_db.lock().unwrap();
+        let gone = conn_db
+            .iter()
+            .filter(|(_, v)| v.addr == addr)
+            .map(|(k, _)| *k)
+            .collect();
+        conn_db.retain(|_, v| v.addr != addr);
+        gone
+    };
+
+    let mut presence_db = presence_db.lock().unwrap();
+    presence_db.unsubscribe(&addr);
+
+    for key in gone {
+        let watchers = presence_db.set_offline(&key);
+        notify_presence(key, false, &watchers, notify_db);
+    }
+}
+
+// Tells the given connections that a user went online or offline
+fn notify_presence(
+    key: VerifyingKey,
+    online: bool,
+    watchers: &[SocketAddr],
+    notify_db: &Mutex<NotifyDb>,
+) {
+    let notify_db = notify_db.lock().unwrap();
+    for tx in watchers.iter().filter_map(|addr| notify_db.get(addr)) {
+        let _ = tx.try_send(Notify::Presence(key, online));
+    }
+}
+
+// Finds a registered user that still has a live connection, along with
+// the candidates it can be reached at
+fn lookup(
+    key: &VerifyingKey,
+    conn_db: &Mutex<ConnectionDb>,
+    notify_db: &Mutex<NotifyDb>,
+) -> Option<(Vec<Candidate>, mpsc::Sender<Notify>)> {
+    let registration = conn_db.lock().unwrap().get(key).cloned()?;
+    let tx = notify_db.lock().unwrap().get(&registration.addr).cloned()?;
+    Some((registration.candidates(), tx))
+}
+
+// Tells a local user to expect a connection and returns its candidates
+async fn call(
+    callee: &VerifyingKey,
+    caller: VerifyingKey,
+    caller_candidates: Vec<Candidate>,
+    conn_db: &Mutex<ConnectionDb>,
+    notify_db: &Mutex<NotifyDb>,
+    stats: &Stats,
+) -> Option<Vec<Candidate>> {
+    let (callee_candidates, callee_tx) = lookup(callee, conn_db, notify_db)?;
+
+    // The callee may disconnect at any point, in which case it simply
+    // won't learn about the call
+    callee_tx
+        .send(Notify::Call(caller, caller_candidates))
+        .await
+        .ok()?;
+
+    stats.notifications.fetch_add(1, Ordering::Relaxed);
+    Some(callee_candidates)
+}
+
+// Tells a user why it's being disconnected and gives it a moment to read
+// the reason before the connection is dropped
+async fn refuse(
+    mut tx: SplitSink<UserTransport, RelayResponse>,
+    conn: &Connection,
+    code: ErrorCode,
+    message: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    event!(Level::INFO, "Refusing {} ({code}): {message}", conn.remote_address());
+
+    let message = message.to_string();
+    tx.send(RelayResponse::Error { code, message }).await?;
+    let _ = tokio::time::timeout(REFUSE_TIMEOUT, conn.closed()).await;
+
+    Ok(())
+}
+
+// The reply to a lookup, successful or not
+fn lookup_response(candidates: Option<Vec<Candidate>>) -> RelayResponse {
+    match candidates {
+        Some(candidates) => RelayResponse::UserAddress(candidates),
+        None => RelayResponse::Error {
+            code: ErrorCode::UnknownUser,
+            message: String::from("The user isn't connected"),
+        },
+    }
+}
+
+// Answers lookups made by a federated relay on behalf of its users
+async fn serve_federation(
+    mut tx: SplitSink<UserTransport, RelayResponse>,
+    mut rx: SplitStream<UserTransport>,
+    conn_db: Arc<Mutex<ConnectionDb>>,
+    notify_db: Arc<Mutex<NotifyDb>>,
+    stats: Arc<Stats>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    while let Some(msg) = rx.next().await {
+        match msg? {
+            RelayRequest::Lookup { callee, caller, caller_candidates } => {
+                stats.lookups.fetch_add(1, Ordering::Relaxed);
+                let callee_candidates = call(
+                    &callee,
+                    caller,
+                    caller_candidates,
+                    &conn_db,
+                    &notify_db,
+                    &stats,
+                )
+                .await;
+                tx.send(lookup_response(callee_candidates)).await?;
+            }
+            RelayRequest::Bye => break,
+            _ => {
+                tx.send(RelayResponse::Error {
+                    code: ErrorCode::ProtocolViolation,
+                    message: String::from("Expected a lookup"),
+                })
+                .await?;
+                return Err("Protocol violation: expected Lookup".into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reads the recipient of a tunnel opened by a client and hands the stream
+// over to the task that handles the recipient's connection
+async fn route_tunnel(
+    from: VerifyingKey,
+    mut reader: RecvStream,
+    writer: SendStream,
+    conn_db: Arc<Mutex<ConnectionDb>>,
+    notify_db: Arc<Mutex<NotifyDb>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut key = [0; 32];
+    reader.read_exact(&mut key).await?;
+    let to = VerifyingKey::from_bytes(&key)?;
+
+    let (_, tx) = lookup(&to, &conn_db, &notify_db)
+        .ok_or("Tunnel recipient isn't connected")?;
+
+    event!(Level::DEBUG, "Routing a tunnel to {:?}", to.as_bytes());
+    tx.send(Notify::Tunnel(from, tokio::io::join(reader, writer)))
+        .await
+        .map_err(|_| "Tunnel recipient disconnected")?;
+
+    Ok(())
+}
+
+// Opens a stream to the tunnel recipient and forwards the already
+// end-to-end encrypted traffic between both clients
+async fn forward_tunnel(
+    conn: Connection,
+    from: VerifyingKey,
+    mut stream: QuicStream,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut writer, reader) = conn.open_bi().await?;
+    writer.write_all(from.as_bytes()).await?;
+    let mut recipient = tokio::io::join(reader, writer);
+
+    let (sent, received) =
+        tokio::io::copy_bidirectional(&mut stream, &mut recipient).await?;
+
+    event!(Level::DEBUG, "Tunnel closed after relaying {sent}/{received} bytes");
+
+    Ok(())
+}
+
+/// What every connection to the relay shares
+#[derive(Clone)]
+struct RelayContext {
     db: Arc<Mutex<UserDb>>,
     conn_db: Arc<Mutex<ConnectionDb>>,
     notify_db: Arc<Mutex<NotifyDb>>,
+    presence_db: Arc<Mutex<PresenceDb>>,
+    federation: Arc<Federation>,
+    access: Arc<Mutex<AccessControl>>,
+    stats: Arc<Stats>,
+    max_connections: Arc<AtomicUsize>,
+    probe_port: Option<u16>,
+}
+
+async fn process(
+    conn: Incoming,
+    context: RelayContext,
+    notify_tx: mpsc::Sender<Notify>,
     mut notify_rx: mpsc::Receiver<Notify>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let RelayContext {
+        db,
+        conn_db,
+        notify_db,
+        presence_db,
+        federation,
+        access,
+        stats,
+        probe_port,
+        ..
+    } = context;
+
     let addr = conn.remote_address();
+    let reflexive_address = RelayResponse::ReflexiveAddress {
+        observed: addr,
+        probe_port,
+    };
     let conn = conn.await?;
 
     let (writer, reader) = conn.accept_bi().await?;
@@ -84,77 +413,218 @@ async fn process(
     .set_my_type(NoiseSelfType::K)
     .set_peer_type(NoisePeerType::I)
     .build_as_responder()
-    .await
-    .expect("Handshake error");
+    .await?;
 
-    let mut stream = NoiseTransport::<QuicStream, RelayResponse, RelayRequest>::new(socket);
+    let mut stream = UserTransport::new(socket);
 
     let remote_noise_key = Vec::<u8>::from(stream.get_ref().get_remote_static().unwrap());
     let (mut tx, mut rx) = stream.split();
 
-    let msg = rx.next().await.unwrap()?;
-    let remote_identity_key = match msg {
-        RelayRequest::Register(pubkey) => {
-            event!(Level::DEBUG, "Received a registration request.");
-            if utils::ed25519_verifying_to_x25519(&pubkey) != remote_noise_key {
-                Err("Protocol violation: registration key doesn't match")
-            }
-            else {
-                Ok(pubkey)
+    let msg = rx.next().await.ok_or("Connection closed before registering")??;
+    let (remote_identity_key, invite) = match msg {
+        RelayRequest::Register(pubkey) => (pubkey, None),
+        RelayRequest::RegisterInvited(pubkey, invite) => (pubkey, Some(invite)),
+        RelayRequest::Federate(pubkey) => {
+            if utils::ed25519_verifying_to_x25519(&pubkey) != remote_noise_key
+                || !federation.is_member(&pubkey)
+            {
+                let message = "This relay isn't federated with you";
+                return refuse(tx, &conn, ErrorCode::NotAllowed, message).await;
             }
+
+            tx.send(RelayResponse::Ack).await?;
+            event!(Level::INFO, "Federated with the relay at {addr}");
+            return serve_federation(tx, rx, conn_db, notify_db, stats).await;
+        }
+        RelayRequest::GetReflexiveAddress => {
+            // A probe from a client that's registered through another
+            // connection, it closes the connection once it has the answer
+            tx.send(reflexive_address).await?;
+            let _ = tokio::time::timeout(REFUSE_TIMEOUT, conn.closed()).await;
+            return Ok(());
         }
         _ => {
-            Err("Protocol violation: expected Register")
+            let message = "Expected a registration";
+            return refuse(tx, &conn, ErrorCode::ProtocolViolation, message).await;
         }
-    }?;
+    };
 
-    tx.send(RelayResponse::Ack).await?;
+    event!(Level::DEBUG, "Received a registration request.");
+    if utils::ed25519_verifying_to_x25519(&remote_identity_key) != remote_noise_key
+        || peer_identity(&conn) != Some(remote_identity_key)
+    {
+        let message = "The registration key doesn't match the session key";
+        return refuse(tx, &conn, ErrorCode::ProtocolViolation, message).await;
+    }
 
-    let _guard = {
-        let mut db = conn_db.lock().unwrap();
-        db.insert(remote_identity_key, addr);
+    let refusal = {
+        let mut access = access.lock().unwrap();
+        if access.is_banned(&remote_identity_key) {
+            Some((ErrorCode::NotAllowed, "You are banned from this relay"))
+        }
+        else if !access.allow_registration(remote_identity_key) {
+            Some((ErrorCode::RateLimited, "Too many registration attempts"))
+        }
+        else if !access.admit(&remote_identity_key, invite.as_ref()) {
+            Some((ErrorCode::NotAllowed, "This relay requires an invite"))
+        }
+        else {
+            None
+        }
     };
 
+    if let Some((code, message)) = refusal {
+        return refuse(tx, &conn, code, message).await;
+    }
+
+    tx.send(RelayResponse::Ack).await?;
+
+    register(remote_identity_key, addr, &notify_tx, &conn_db, &notify_db);
+
     event!(Level::INFO, "Registered a new user: {:?}", remote_identity_key.as_bytes());
 
+    // A user returning after a relay restart gets its presence back even
+    // before it shares it again
+    let (watchers, online) = presence_db
+        .lock()
+        .unwrap()
+        .restore(remote_identity_key, addr);
+    notify_presence(remote_identity_key, true, &watchers, &notify_db);
+    for key in online {
+        tx.send(RelayResponse::Presence(key, true)).await?;
+    }
+
     loop {
         tokio::select! {
-            Some(Ok(msg)) = rx.next() => {
+            msg = rx.next() => {
+                let Some(Ok(msg)) = msg
+                else {
+                    event!(Level::DEBUG, "Connection with {addr} lost");
+                    break;
+                };
+
                 match msg {
-                    RelayRequest::Register(pubkey) => {
+                    RelayRequest::Register(_) | RelayRequest::RegisterInvited(..) => {
                         event!(Level::DEBUG, "Received another registration request. Ignoring.");
                         tx.send(RelayResponse::Ack).await?;
                     }
+                    RelayRequest::GetUser(_)
+                        if !access
+                            .lock()
+                            .unwrap()
+                            .allow_lookup(remote_identity_key, addr.ip()) =>
+                    {
+                        tx.send(RelayResponse::Error {
+                            code: ErrorCode::RateLimited,
+                            message: String::from("Too many lookups, slow down"),
+                        })
+                        .await?;
+                    }
                     RelayRequest::GetUser(pubkey) => {
-                        let result = {
-                            let db = conn_db.lock().unwrap();
-                            db.get(&pubkey).and_then(|addr| Some(addr.clone()))
-                        };
-
-                        let db = notify_db.clone();
-
-                        tokio::join!(
-                            tx.send(RelayResponse::UserAddress(result.clone())),
-                            async move {
-                                let tx = {
-                                    let mut db = db.lock().unwrap();
-                                    db.get(&result.unwrap()).unwrap().clone()
-                                };
-                                tx.send(Notify::Call((addr.clone()))).await;
-                            }
-                        );
+                        stats.lookups.fetch_add(1, Ordering::Relaxed);
+                        let caller_candidates = conn_db
+                            .lock()
+                            .unwrap()
+                            .get(&remote_identity_key)
+                            .map_or_else(
+                                || vec![Candidate::new(CandidateKind::Reflexive, addr)],
+                                Registration::candidates,
+                            );
+
+                        let mut callee_candidates = call(
+                            &pubkey,
+                            remote_identity_key,
+                            caller_candidates.clone(),
+                            &conn_db,
+                            &notify_db,
+                            &stats,
+                        )
+                        .await;
+
+                        // The callee may be registered with a federated relay
+                        if callee_candidates.is_none() {
+                            callee_candidates = federation
+                                .lookup(pubkey, remote_identity_key, caller_candidates)
+                                .await;
+                        }
+
+                        tx.send(lookup_response(callee_candidates)).await?;
+                    }
+                    RelayRequest::SetCandidates(mut candidates) => {
+                        candidates.truncate(MAX_CANDIDATES);
+                        if let Some(registration) = conn_db
+                            .lock()
+                            .unwrap()
+                            .get_mut(&remote_identity_key)
+                            .filter(|registration| registration.addr == addr)
+                        {
+                            registration.candidates = candidates;
+                        }
+                    }
+                    RelayRequest::SetPresenceTokens(tokens) => {
+                        let watchers = presence_db
+                            .lock()
+                            .unwrap()
+                            .set_online(remote_identity_key, tokens);
+                        notify_presence(remote_identity_key, true, &watchers, &notify_db);
+                    }
+                    RelayRequest::Subscribe(subscriptions) => {
+                        let online = presence_db
+                            .lock()
+                            .unwrap()
+                            .subscribe(addr, remote_identity_key, subscriptions);
+                        for key in online {
+                            tx.send(RelayResponse::Presence(key, true)).await?;
+                        }
+                    }
+                    RelayRequest::GetReflexiveAddress => {
+                        tx.send(reflexive_address.clone()).await?;
+                    }
+                    RelayRequest::Federate(_) | RelayRequest::Lookup { .. } => {
+                        let message = "Only relays may send relay requests";
+                        return refuse(tx, &conn, ErrorCode::ProtocolViolation, message).await;
                     }
                     RelayRequest::Ack => {}
-                    RelayRequest::Bye => break,
+                    RelayRequest::Bye => {
+                        event!(Level::DEBUG, "{addr} said goodbye");
+                        break;
+                    }
                 }
             }
+            Ok((writer, reader)) = conn.accept_bi() => {
+                tokio::spawn(route_tunnel(
+                    remote_identity_key,
+                    reader,
+                    writer,
+                    conn_db.clone(),
+                    notify_db.clone(),
+                ));
+            }
             Some(notification) = notify_rx.recv() => {
-                let Notify::Call(addr) = notification;
-                let key = {
-                    let db = conn_db.lock().unwrap();
-                    db.iter().filter(|(k, v)| **v == addr).next().unwrap().0.clone()
-                };
-                tx.send(RelayResponse::AwaitConnection(key, addr)).await?;
+                match notification {
+                    Notify::Call(key, candidates) => {
+                        tx.send(RelayResponse::AwaitConnection(key, candidates)).await?;
+                    }
+                    Notify::Tunnel(from, stream) => {
+                        event!(Level::INFO, "Relaying a peer connection");
+                        tokio::spawn(forward_tunnel(conn.clone(), from, stream));
+                    }
+                    Notify::Replaced(new_addr) => {
+                        event!(Level::INFO, "{addr} was replaced by {new_addr}");
+                        conn.close(0u32.into(), b"registered elsewhere");
+                        break;
+                    }
+                    Notify::Presence(key, online) => {
+                        tx.send(RelayResponse::Presence(key, online)).await?;
+                    }
+                    Notify::Kick(reason) => {
+                        return refuse(tx, &conn, ErrorCode::NotAllowed, &reason).await;
+                    }
+                }
+            }
+            reason = conn.closed() => {
+                event!(Level::DEBUG, "Connection with {addr} closed: {reason}");
+                break;
             }
         }
     }
@@ -162,17 +632,13 @@ async fn process(
     Ok(())
 }
 
-fn get_default_path() -> PathBuf {
-    dirs::data_dir()
-        .unwrap()
-        .join("aluminum")
-        .join("server.db")
-}
-
 /// Aluminum relay server
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Prints your identity to stdout
     #[arg(long, value_name = "PATH")]
     print_public: bool,
@@ -183,17 +649,116 @@ struct Args {
 
     /// Creates a new identity with given Ed25519 private key in Base64
     #[arg(long, value_name = "PATH")]
-    with_key: Option<String>
+    with_key: Option<String>,
+
+    /// Reads the configuration from a TOML file
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, can be given multiple times
+    #[arg(short, long, value_name = "ADDR")]
+    listen: Vec<SocketAddr>,
+
+    /// Path to the server database
+    #[arg(long, value_name = "PATH")]
+    db: Option<PathBuf>,
+
+    /// Path to the log file, "-" logs to stderr
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Default log level, overridden by RUST_LOG
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// Keepalive interval in seconds
+    #[arg(long, value_name = "SECS")]
+    keepalive: Option<u64>,
+
+    /// Idle timeout in seconds
+    #[arg(long, value_name = "SECS")]
+    idle_timeout: Option<u64>,
+
+    /// Maximum number of simultaneous connections
+    #[arg(long, value_name = "COUNT")]
+    max_connections: Option<usize>,
+
+    /// Prints an invite for the user with the given Base64 public key
+    #[arg(long, value_name = "KEY")]
+    invite: Option<String>,
+
+    /// Number of days after which the printed invite expires
+    #[arg(long, value_name = "DAYS", requires = "invite")]
+    invite_days: Option<i64>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Sends a command to a running relay: stats, users, kick <key>,
+    /// ban <key>, unban <key> or reload
+    Admin {
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+}
+
+impl Args {
+    fn load_config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => {
+                let path = config::get_default_config_path();
+                if path.exists() {
+                    Config::load(&path)?
+                }
+                else {
+                    Config::default()
+                }
+            }
+        };
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        if let Some(db) = &self.db {
+            config.database = db.clone();
+        }
+        if let Some(log_file) = &self.log_file {
+            config.log_file = log_file.clone();
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(keepalive) = self.keepalive {
+            config.keepalive_secs = keepalive;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.idle_timeout_secs = idle_timeout;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = max_connections;
+        }
+
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     color_eyre::install()?;
-    let _guard = init_tracing()?;
 
     let args = Args::parse();
+    let config = args.load_config()?;
 
-    let path = get_default_path();
+    if let Some(Command::Admin { command }) = &args.command {
+        let reply = admin::send_command(&config.admin_socket, command).await?;
+        print!("{reply}");
+        return Ok(());
+    }
+
+    let (_guard, set_log_level) = init_tracing(&config)?;
+
+    let path = config.database.clone();
     let serverdb = if path.exists() {
         UserDb::load(&path)
     }
@@ -204,11 +769,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .nickname(String::from("server"))
             .description(String::from("serwuje użytkowników z tradycją od 2024 roku"));
 
-        if let Some(key) = args.with_key {
+        if let Some(key) = &args.with_key {
             let private = BASE64_STANDARD.decode(key)?;
             myself = myself.with_key(SigningKey::from_bytes(&private.try_into().unwrap()));
         };
         
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
         let db = UserDb::new(
             path,
             myself.build()
@@ -219,9 +788,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         db
     };
 
+    let state = RelayState::load(&config.state)?;
+
     let conndb = Arc::new(Mutex::new(ConnectionDb::new()));
     let notifydb = Arc::new(Mutex::new(NotifyDb::new()));
-    
+    let presencedb = Arc::new(Mutex::new(PresenceDb::new(state.presence)));
+
     if args.print_public {
         let public = serverdb.myself.get_public_key();
         println!("{}", BASE64_STANDARD.encode(public.as_bytes()));
@@ -232,50 +804,211 @@ async fn main() -> Result<(), Box<dyn Error>> {
         println!("{}", BASE64_STANDARD.encode(private.as_bytes()));
         return Ok(());
     }
+    else if let Some(invitee) = &args.invite {
+        let invitee = BASE64_STANDARD.decode(invitee)?;
+        let invitee = VerifyingKey::from_bytes(
+            &invitee.try_into().map_err(|_| "Invalid public key length")?,
+        )?;
+        let expires = args
+            .invite_days
+            .map(|days| chrono::Utc::now() + chrono::Duration::days(days));
+
+        let invite = Invite::new(serverdb.get_master_key(), invitee, expires);
+        println!("{}", invite.encode());
+        return Ok(());
+    }
+
+    let access = {
+        let mut access =
+            AccessControl::new(&config, serverdb.myself.get_public_key())?;
+        for key in state.banned {
+            access.ban(key);
+        }
+        Arc::new(Mutex::new(access))
+    };
+
+    // Before anything else is set up, so that a second instance started by
+    // mistake doesn't get far
+    let admin_listener = admin::bind(&config.admin_socket).await?;
+    event!(Level::INFO, "Admin socket listening on {:?}", config.admin_socket);
 
     rustls::crypto::ring::default_provider().install_default();
 
     let serverdb = Arc::new(Mutex::new(serverdb));
 
-    let addr: SocketAddr = "0.0.0.0:55007".parse().unwrap();
-    let (endpoint, _server_cert) = make_server_endpoint(addr).unwrap();
+    let endpoints =
+        make_server_endpoints(&config, serverdb.lock().unwrap().get_master_key())
+            .unwrap();
+
+    let federation = {
+        let db = serverdb.lock().unwrap();
+        Arc::new(Federation::spawn(
+            &config.federation,
+            &endpoints,
+            db.get_master_key(),
+        ))
+    };
+    let stats = Arc::new(Stats::new());
+    let max_connections = Arc::new(AtomicUsize::new(config.max_connections));
+
+    let admin = Arc::new(Admin {
+        stats: stats.clone(),
+        conn_db: conndb.clone(),
+        notify_db: notifydb.clone(),
+        access: access.clone(),
+        max_connections: max_connections.clone(),
+        config: Mutex::new(config.clone()),
+        load_config: Box::new(move || args.load_config()),
+        set_log_level,
+        identity: serverdb.lock().unwrap().get_master_key().clone(),
+        endpoints: endpoints.clone(),
+    });
+
+    let admin_server = admin.clone();
+    tokio::spawn(async move {
+        if let Err(e) = admin_server.serve(admin_listener).await {
+            event!(Level::WARN, "Admin socket failed: {e}");
+        }
+    });
+
+    if let Some(addr) = config.metrics_listen {
+        let metrics_server = admin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.serve_metrics(addr).await {
+                event!(Level::WARN, "Metrics endpoint failed: {e}");
+            }
+        });
+    }
+
+    tokio::spawn(state::save_periodically(
+        config.state.clone(),
+        access.clone(),
+        presencedb.clone(),
+    ));
+
+    let context = RelayContext {
+        db: serverdb,
+        conn_db: conndb,
+        notify_db: notifydb,
+        presence_db: presencedb.clone(),
+        federation,
+        access: access.clone(),
+        stats,
+        max_connections: max_connections.clone(),
+        probe_port: config.probe_port,
+    };
+
+    let listeners = endpoints.iter().map(|endpoint| {
+        event!(Level::INFO, "Listening on {}", endpoint.local_addr().unwrap());
+        tokio::spawn(listen(endpoint.clone(), context.clone()))
+    });
+
+    tokio::select! {
+        _ = join_all(listeners) => {}
+        result = shutdown_signal() => {
+            result?;
+            event!(Level::INFO, "Shutting down");
+        }
+    }
+
+    RelayState::collect(&access, &presencedb).save(&config.state)?;
+    let _ = std::fs::remove_file(&config.admin_socket);
+
+    // Closing the connections lets clients reconnect as soon as the relay
+    // is back, instead of waiting for the idle timeout
+    for endpoint in &endpoints {
+        endpoint.close(0u32.into(), b"relay restarting");
+    }
+    let _ = tokio::time::timeout(
+        SHUTDOWN_TIMEOUT,
+        join_all(endpoints.iter().map(Endpoint::wait_idle)),
+    )
+    .await;
+
+    Ok(())
+}
+
+// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+async fn listen(endpoint: Endpoint, context: RelayContext) {
+    let stats = &context.stats;
 
     while let Some(conn) = endpoint.accept().await {
         let addr = conn.remote_address();
+
+        // Refusing at the QUIC level is cheaper than finishing a handshake
+        // just to send an error
+        if !context.access.lock().unwrap().allow_connection(addr.ip()) {
+            event!(Level::WARN, "Refusing {addr}, too many connection attempts");
+            conn.refuse();
+            continue;
+        }
+
+        let max_connections = context.max_connections.load(Ordering::SeqCst);
+        if stats.connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+            stats.connections.fetch_sub(1, Ordering::SeqCst);
+            event!(Level::WARN, "Refusing {addr}, connection limit reached");
+            conn.refuse();
+            continue;
+        }
+
         let (tx, rx) = mpsc::channel(32);
-        let _guard = {
-            let mut db = notifydb.lock().unwrap();
-            db.insert(addr, tx);
-        };
         event!(
             Level::INFO,
             "Handling a new connection from {}",
             conn.remote_address()
         );
-        tokio::spawn(process(
-            conn,
-            serverdb.clone(),
-            conndb.clone(),
-            notifydb.clone(),
-            rx,
-        ));
-    };
 
-    Ok(())
+        stats.connections_total.fetch_add(1, Ordering::Relaxed);
+
+        let process = process(conn, context.clone(), tx.clone(), rx);
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process.await {
+                event!(Level::INFO, "Connection with {addr} failed: {e}");
+            }
+            unregister(addr, &tx, &context.conn_db, &context.notify_db, &context.presence_db);
+            context.stats.connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
 }
 
-fn init_tracing() -> Result<WorkerGuard> {
-    let file = File::create("server.log")?;
-    let (non_blocking, guard) = non_blocking(file);
+fn init_tracing(config: &Config) -> Result<(WorkerGuard, SetLogLevel)> {
+    let (non_blocking, guard) = if config.log_file.as_os_str() == "-" {
+        non_blocking(std::io::stderr())
+    }
+    else {
+        non_blocking(File::create(&config.log_file)?)
+    };
 
-    let env_filter = EnvFilter::builder()
-        .with_default_directive(Level::DEBUG.into())
-        .from_env_lossy();
+    // RUST_LOG still takes precedence over the configured level
+    let env_filter = |level: Level| {
+        EnvFilter::builder()
+            .with_default_directive(level.into())
+            .from_env_lossy()
+    };
 
-    tracing_subscriber::fmt()
+    let level: Level = config.log_level.parse()?;
+    let subscriber = tracing_subscriber::fmt()
         .with_writer(non_blocking)
-        .with_env_filter(env_filter)
-        .init();
-
-    Ok(guard)
+        .with_env_filter(env_filter(level))
+        .with_filter_reloading();
+    let handle = subscriber.reload_handle();
+    subscriber.init();
+
+    let set_log_level: SetLogLevel = Box::new(move |level| {
+        let level: Level = level.parse()?;
+        handle.reload(env_filter(level))?;
+        Ok(())
+    });
+
+    Ok((guard, set_log_level))
 }
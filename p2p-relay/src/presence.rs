@@ -0,0 +1,138 @@
+use chrono::{Duration, Utc};
+use ed25519_dalek::VerifyingKey;
+use libchatty::utils::PresenceToken;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use crate::state::SavedPresence;
+
+// Users that haven't connected for this long are forgotten
+const SAVED_PRESENCE_DAYS: i64 = 30;
+
+/// Keeps track of who is online and who wants to know about it.
+/// A subscriber only learns about a user's presence if its token is one of
+/// the tokens that the user registered, i.e. if they're mutual contacts.
+#[derive(Debug, Default)]
+pub struct PresenceDb {
+    // Tokens accepted by each online user
+    tokens: HashMap<VerifyingKey, HashSet<PresenceToken>>,
+    // Subscriptions made by each connection
+    subscriptions: HashMap<SocketAddr, Vec<(VerifyingKey, PresenceToken)>>,
+    // The last tokens and subscriptions of every user, kept across
+    // connections and restarts
+    saved: HashMap<VerifyingKey, SavedPresence>,
+}
+
+impl PresenceDb {
+    pub fn new(saved: HashMap<VerifyingKey, SavedPresence>) -> Self {
+        let mut presence_db = Self {
+            saved,
+            ..Self::default()
+        };
+
+        presence_db.expire();
+        presence_db
+    }
+
+    /// Forgets what users that have been away for too long told the relay
+    pub fn expire(&mut self) {
+        let cutoff = Utc::now() - Duration::days(SAVED_PRESENCE_DAYS);
+        let online = &self.tokens;
+        self.saved.retain(|key, saved| {
+            online.contains_key(key) || saved.last_seen > cutoff
+        });
+    }
+
+    pub fn saved(&self) -> &HashMap<VerifyingKey, SavedPresence> {
+        &self.saved
+    }
+
+    /// Marks a user as online and returns the connections that should be
+    /// told about it
+    pub fn set_online(
+        &mut self,
+        key: VerifyingKey,
+        tokens: Vec<PresenceToken>,
+    ) -> Vec<SocketAddr> {
+        let tokens: HashSet<PresenceToken> = tokens.into_iter().collect();
+        let saved = self.saved.entry(key).or_default();
+        saved.tokens = tokens.clone();
+        saved.last_seen = Utc::now();
+        self.tokens.insert(key, tokens);
+        self.get_watchers(&key)
+    }
+
+    /// Marks a user as offline and returns the connections that should be
+    /// told about it
+    pub fn set_offline(&mut self, key: &VerifyingKey) -> Vec<SocketAddr> {
+        if let Some(saved) = self.saved.get_mut(key) {
+            saved.last_seen = Utc::now();
+        }
+
+        let watchers = self.get_watchers(key);
+        self.tokens.remove(key);
+        watchers
+    }
+
+    /// Replaces the subscriptions of a connection and returns the subscribed
+    /// users that are currently online
+    pub fn subscribe(
+        &mut self,
+        addr: SocketAddr,
+        key: VerifyingKey,
+        subscriptions: Vec<(VerifyingKey, PresenceToken)>,
+    ) -> Vec<VerifyingKey> {
+        self.saved.entry(key).or_default().subscriptions = subscriptions.clone();
+
+        let online = subscriptions
+            .iter()
+            .filter(|(key, token)| self.is_visible(key, token))
+            .map(|(key, _)| *key)
+            .collect();
+
+        self.subscriptions.insert(addr, subscriptions);
+        online
+    }
+
+    /// Brings back what a returning user told the relay before, so that its
+    /// contacts see it online right away. Returns the connections that
+    /// should be told about it and the subscribed users that are online.
+    pub fn restore(
+        &mut self,
+        key: VerifyingKey,
+        addr: SocketAddr,
+    ) -> (Vec<SocketAddr>, Vec<VerifyingKey>) {
+        let Some(saved) = self.saved.get(&key).cloned()
+        else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let watchers = self.set_online(key, saved.tokens.into_iter().collect());
+        let online = self.subscribe(addr, key, saved.subscriptions);
+        (watchers, online)
+    }
+
+    pub fn unsubscribe(&mut self, addr: &SocketAddr) {
+        self.subscriptions.remove(addr);
+    }
+
+    fn is_visible(&self, key: &VerifyingKey, token: &PresenceToken) -> bool {
+        self.tokens
+            .get(key)
+            .is_some_and(|tokens| tokens.contains(token))
+    }
+
+    fn get_watchers(&self, key: &VerifyingKey) -> Vec<SocketAddr> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, subscriptions)| {
+                subscriptions.iter().any(|(subscribed, token)| {
+                    subscribed == key && self.is_visible(key, token)
+                })
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
@@ -0,0 +1,107 @@
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use libchatty::utils::PresenceToken;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::interval;
+use tracing::{event, Level};
+
+use crate::{access::AccessControl, presence::PresenceDb};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a user last told the relay about its presence
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedPresence {
+    pub tokens: HashSet<PresenceToken>,
+    pub subscriptions: Vec<(VerifyingKey, PresenceToken)>,
+    /// When the user was last connected, users that stay away for too
+    /// long are forgotten
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Default for SavedPresence {
+    fn default() -> Self {
+        Self {
+            tokens: HashSet::new(),
+            subscriptions: Vec::new(),
+            last_seen: Utc::now(),
+        }
+    }
+}
+
+/// Relay state that outlives a restart. Connections themselves can't be
+/// kept, clients register again and get their presence back.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct RelayState {
+    pub banned: HashSet<VerifyingKey>,
+    pub presence: HashMap<VerifyingKey, SavedPresence>,
+}
+
+impl RelayState {
+    /// Loads the state, a missing file means a fresh relay
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let serialized = match fs::read(path) {
+            Ok(serialized) => serialized,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        postcard::from_bytes(&serialized)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the state to a temporary file first, so that a crash while
+    /// saving can't leave a truncated file behind
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let serialized = postcard::to_allocvec(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serialized)?;
+        fs::rename(&temporary, path)
+    }
+
+    pub fn collect(
+        access: &Mutex<AccessControl>,
+        presence_db: &Mutex<PresenceDb>,
+    ) -> Self {
+        Self {
+            banned: access.lock().unwrap().banned().clone(),
+            presence: presence_db.lock().unwrap().saved().clone(),
+        }
+    }
+}
+
+/// Saves the state whenever it has changed
+pub async fn save_periodically(
+    path: PathBuf,
+    access: Arc<Mutex<AccessControl>>,
+    presence_db: Arc<Mutex<PresenceDb>>,
+) {
+    let mut last_saved = RelayState::collect(&access, &presence_db);
+    let mut ticker = interval(SAVE_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        presence_db.lock().unwrap().expire();
+        let state = RelayState::collect(&access, &presence_db);
+        if state == last_saved {
+            continue;
+        }
+
+        match state.save(&path) {
+            Ok(()) => last_saved = state,
+            Err(e) => event!(Level::WARN, "Couldn't save the relay state: {e}"),
+        }
+    }
+}